@@ -1,7 +1,9 @@
 use crypto::aead::{AeadDecryptor, AeadEncryptor};
 use crypto::aes::KeySize::KeySize256;
 use crypto::aes_gcm::AesGcm;
+use crypto::curve25519::{curve25519, curve25519_base};
 use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::scrypt::{scrypt, ScryptParams};
 use crypto::sha2::Sha256;
 use rand::{thread_rng, RngCore};
 
@@ -10,16 +12,41 @@ use rand::{thread_rng, RngCore};
 pub const CHUNK_SIZE: u64 = 1048576; // 1MB
 // pub const CHUNK_SIZE: u64 = 1024; // 1KB
 // pub const CHUNK_SIZE: u64 = 512; // 512 bytes
-pub const ENCRYPTED_CHUNK_SIZE: u64 = CHUNK_SIZE + 44; // that's what encrypted chunks end up being
-// for decryption, we need to use ENCRYPTED_CHUNK_SIZE
+// files written before the chunk size was recorded in the header were all chunked at 1MB,
+// keep this fixed so they stay readable whatever CHUNK_SIZE becomes
+pub const LEGACY_CHUNK_SIZE: u64 = 1048576;
+pub const ENCRYPTED_CHUNK_SIZE: u64 = LEGACY_CHUNK_SIZE + 44; // that's what encrypted chunks end up being
+// for decryption of data encrypted with encrypt_data, we need to use ENCRYPTED_CHUNK_SIZE
 
-const SALT_SIZE: usize = 16;
+pub const SALT_SIZE: usize = 16;
 const NONCE_SIZE: usize = 12;
 const KEY_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+pub const PUBLIC_KEY_SIZE: usize = 32;
 
-/// these 2 are template encryption functions using rust-crypto-wasm to run
-/// aes_gcm has trouble compiling to wasm using apple clang, it'd be cleaner with it
-/// feel free to refactor better!
+// session format: every encrypted file starts with a header
+// magic | version | cipher id | kdf id | salt | kdf params | chunk size | file index
+// followed by chunks of length | ciphertext | tag, each chunk size + FRAME_SIZE + 16 except the last one
+const MAGIC: &[u8; 4] = b"FTEF";
+pub const FORMAT_VERSION: u8 = 3;
+// older versions, still read:
+// 1: magic | 1 | salt | file index, key derived with HKDF
+// 2: magic | 2 | salt | scrypt params | file index, key derived with scrypt
+// both AES-256-GCM with unframed chunks of LEGACY_CHUNK_SIZE
+const VERSION_HKDF: u8 = 1;
+const VERSION_SCRYPT: u8 = 2;
+const KDF_HKDF: u8 = 1;
+const KDF_SCRYPT: u8 = 2;
+// log_n | r | p
+const SCRYPT_PARAMS_SIZE: usize = 1 + 4 + 4;
+// big endian u32 length in front of every chunk
+pub const FRAME_SIZE: u64 = 4;
+// counter reserved for the file name, chunks count up from 0
+const NAME_COUNTER: u64 = u64::MAX;
+
+// these 2 are template encryption functions using rust-crypto-wasm to run
+// aes_gcm has trouble compiling to wasm using apple clang, it'd be cleaner with it
+// feel free to refactor better!
 
 pub fn encrypt_data(data: &[u8], password: &str) -> Vec<u8> {
     let mut rng = thread_rng();
@@ -86,3 +113,525 @@ pub fn decrypt_data(
         Err("Decryption failed".into())
     }
 }
+
+// x25519 key exchange, so nodes can encrypt to each other without a pre-shared password.
+// the receiver advertises its public key, the sender makes an ephemeral keypair per transfer,
+// and both sides derive the same password for encrypt_data/decrypt_data.
+
+// (secret, public)
+pub fn generate_keypair() -> ([u8; KEY_SIZE], [u8; PUBLIC_KEY_SIZE]) {
+    let mut secret = [0u8; KEY_SIZE];
+    thread_rng().fill_bytes(&mut secret);
+    let public = curve25519_base(&secret);
+    (secret, public)
+}
+
+pub fn shared_password(secret: &[u8; KEY_SIZE], peer_public: &[u8; PUBLIC_KEY_SIZE]) -> String {
+    let shared = curve25519(secret, peer_public);
+
+    // the raw shared point is not uniformly random, run it through HKDF
+    let mut prk = [0u8; KEY_SIZE];
+    hkdf_extract(Sha256::new(), b"folder_transfer x25519", &shared, &mut prk);
+    let mut okm = vec![0u8; KEY_SIZE];
+    hkdf_expand(Sha256::new(), &prk, b"", &mut okm);
+
+    okm.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// session encryption: the key is derived once per transfer from the password and a random salt,
+/// and every chunk gets the nonce (file index, chunk index).
+/// file indexes are unique within a transfer and chunk indexes within a file,
+/// so no nonce is ever used twice under the same key.
+
+// how the session key is derived from the password
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kdf {
+    Hkdf,
+    // memory-hard, costs log_n, r and p are stored in the header so they can be raised later
+    Scrypt { log_n: u8, r: u32, p: u32 },
+}
+
+// memory scrypt takes for a derivation: 128 * r * 2^log_n bytes
+const SCRYPT_MAX_MEMORY: u64 = 32 * 1024 * 1024;
+
+fn scrypt_memory(log_n: u8, r: u32) -> u64 {
+    (128 * r as u64).saturating_mul(1u64 << log_n)
+}
+
+impl Kdf {
+    // ~32MB of memory per derivation, which happens once per transfer
+    pub const SCRYPT_DEFAULT: Kdf = Kdf::Scrypt {
+        log_n: 15,
+        r: 8,
+        p: 1,
+    };
+
+    // headers come from stored data, refuse costs we wouldn't pick ourselves
+    // instead of letting a crafted file exhaust our memory (or make scrypt panic):
+    // at most the memory of SCRYPT_DEFAULT, and no parallelism, which would only multiply the time
+    fn check(&self) -> Result<(), String> {
+        match *self {
+            Kdf::Hkdf => Ok(()),
+            Kdf::Scrypt { log_n, r, p } => {
+                // the memory cap bounds r before anything is multiplied with it
+                if (1..32).contains(&log_n)
+                    && r >= 1
+                    && p == 1
+                    && scrypt_memory(log_n, r) <= SCRYPT_MAX_MEMORY
+                    && (log_n as u32) < r * 16
+                {
+                    Ok(())
+                } else {
+                    Err(format!("Unsupported scrypt parameters {} {} {}", log_n, r, p))
+                }
+            }
+        }
+    }
+
+    fn derive(&self, password: &str, salt: &[u8; SALT_SIZE]) -> [u8; KEY_SIZE] {
+        let mut key = [0u8; KEY_SIZE];
+        match *self {
+            Kdf::Hkdf => {
+                let mut prk = [0u8; KEY_SIZE];
+                hkdf_extract(Sha256::new(), salt, password.as_bytes(), &mut prk);
+                hkdf_expand(Sha256::new(), &prk, b"", &mut key);
+            }
+            Kdf::Scrypt { log_n, r, p } => {
+                scrypt(password.as_bytes(), salt, &ScryptParams::new(log_n, r, p), &mut key);
+            }
+        }
+        key
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cipher {
+    Aes256Gcm,
+}
+
+impl Cipher {
+    fn id(&self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Cipher, String> {
+        match id {
+            1 => Ok(Cipher::Aes256Gcm),
+            id => Err(format!("Unknown cipher {}", id)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileHeader {
+    pub version: u8,
+    pub cipher: Cipher,
+    pub kdf: Kdf,
+    pub salt: [u8; SALT_SIZE],
+    // plaintext bytes per chunk
+    pub chunk_size: u64,
+    pub file_index: u32,
+}
+
+impl FileHeader {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(self.version);
+        if self.framed() {
+            bytes.push(self.cipher.id());
+            bytes.push(match self.kdf {
+                Kdf::Hkdf => KDF_HKDF,
+                Kdf::Scrypt { .. } => KDF_SCRYPT,
+            });
+        }
+        bytes.extend_from_slice(&self.salt);
+        if let Kdf::Scrypt { log_n, r, p } = self.kdf {
+            bytes.push(log_n);
+            bytes.extend_from_slice(&r.to_be_bytes());
+            bytes.extend_from_slice(&p.to_be_bytes());
+        }
+        if self.framed() {
+            bytes.extend_from_slice(&(self.chunk_size as u32).to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.file_index.to_be_bytes());
+        bytes
+    }
+
+    // length of the header in bytes, depends on the version and kdf
+    pub fn size(&self) -> usize {
+        let params = match self.kdf {
+            Kdf::Hkdf => 0,
+            Kdf::Scrypt { .. } => SCRYPT_PARAMS_SIZE,
+        };
+        let framed = if self.framed() { 2 + 4 } else { 0 };
+        4 + 1 + framed + SALT_SIZE + params + 4
+    }
+
+    // chunks are prefixed with their length from FORMAT_VERSION on
+    pub fn framed(&self) -> bool {
+        self.version >= FORMAT_VERSION
+    }
+
+    // written by this version of the code, with the current chunk size
+    pub fn is_current(&self) -> bool {
+        self.version == FORMAT_VERSION && self.chunk_size == CHUNK_SIZE
+    }
+
+    // stored size of every chunk but the last one
+    pub fn stored_chunk_size(&self) -> u64 {
+        self.stored_chunk_len(1, self.chunk_size)
+    }
+
+    // stored size of chunk `index` holding `plaintext_len` bytes, the first one carries the header
+    pub fn stored_chunk_len(&self, index: u64, plaintext_len: u64) -> u64 {
+        let header = if index == 0 { self.size() as u64 } else { 0 };
+        let frame = if self.framed() { FRAME_SIZE } else { 0 };
+        header + frame + plaintext_len + TAG_SIZE as u64
+    }
+
+    // where chunk `index` starts in the stored file
+    pub fn chunk_offset(&self, index: u64) -> u64 {
+        if index == 0 {
+            0
+        } else {
+            self.size() as u64 + index * self.stored_chunk_size()
+        }
+    }
+
+    // splits a header off the front of `data`
+    pub fn parse(data: &[u8]) -> Result<(FileHeader, &[u8]), String> {
+        if data.len() < 5 || &data[..4] != MAGIC {
+            return Err("Not a session encrypted file".into());
+        }
+        let version = data[4];
+        let mut rest = &data[5..];
+        let (cipher, kdf_id) = match version {
+            VERSION_HKDF => (Cipher::Aes256Gcm, KDF_HKDF),
+            VERSION_SCRYPT => (Cipher::Aes256Gcm, KDF_SCRYPT),
+            FORMAT_VERSION => {
+                let ids = take(&mut rest, 2)?;
+                (Cipher::from_id(ids[0])?, ids[1])
+            }
+            version => return Err(format!("Unknown format version {}", version)),
+        };
+        let mut salt = [0u8; SALT_SIZE];
+        salt.copy_from_slice(take(&mut rest, SALT_SIZE)?);
+        let kdf = match kdf_id {
+            KDF_HKDF => Kdf::Hkdf,
+            KDF_SCRYPT => {
+                let params = take(&mut rest, SCRYPT_PARAMS_SIZE)?;
+                Kdf::Scrypt {
+                    log_n: params[0],
+                    r: u32::from_be_bytes([params[1], params[2], params[3], params[4]]),
+                    p: u32::from_be_bytes([params[5], params[6], params[7], params[8]]),
+                }
+            }
+            id => return Err(format!("Unknown kdf {}", id)),
+        };
+        kdf.check()?;
+        let chunk_size = if version == FORMAT_VERSION {
+            let bytes = take(&mut rest, 4)?;
+            u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64
+        } else {
+            LEGACY_CHUNK_SIZE
+        };
+        if chunk_size == 0 {
+            return Err("Chunk size is 0".into());
+        }
+        let bytes = take(&mut rest, 4)?;
+        let file_index = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        Ok((
+            FileHeader {
+                version,
+                cipher,
+                kdf,
+                salt,
+                chunk_size,
+                file_index,
+            },
+            rest,
+        ))
+    }
+}
+
+// splits `n` bytes off the front of `data`
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if data.len() < n {
+        return Err("Header is too short".into());
+    }
+    let (taken, rest) = data.split_at(n);
+    *data = rest;
+    Ok(taken)
+}
+
+pub struct SessionKey {
+    key: [u8; KEY_SIZE],
+    kdf: Kdf,
+    salt: [u8; SALT_SIZE],
+}
+
+impl SessionKey {
+    // new session, with a fresh salt
+    pub fn new(password: &str, kdf: Kdf) -> SessionKey {
+        let mut salt = [0u8; SALT_SIZE];
+        thread_rng().fill_bytes(&mut salt);
+        SessionKey::derive(password, kdf, &salt)
+    }
+
+    // the session some data was encrypted in, from the kdf and salt in its header
+    pub fn derive(password: &str, kdf: Kdf, salt: &[u8; SALT_SIZE]) -> SessionKey {
+        SessionKey {
+            key: kdf.derive(password, salt),
+            kdf,
+            salt: *salt,
+        }
+    }
+
+    pub fn salt(&self) -> &[u8; SALT_SIZE] {
+        &self.salt
+    }
+
+    pub fn header(&self, file_index: u32) -> FileHeader {
+        FileHeader {
+            version: FORMAT_VERSION,
+            cipher: Cipher::Aes256Gcm,
+            kdf: self.kdf,
+            salt: self.salt,
+            chunk_size: CHUNK_SIZE,
+            file_index,
+        }
+    }
+
+    // chunk `index` of a file as stored: length | ciphertext | tag, with the header in front of the first one
+    pub fn seal_chunk(&self, file_index: u32, index: u64, data: &[u8]) -> Vec<u8> {
+        let encrypted = self.encrypt_chunk(file_index, index, data);
+        let mut stored = Vec::new();
+        if index == 0 {
+            stored = self.header(file_index).to_bytes();
+        }
+        stored.extend_from_slice(&(encrypted.len() as u32).to_be_bytes());
+        stored.extend_from_slice(&encrypted);
+        stored
+    }
+
+    // the other way around, for a chunk as it arrives
+    pub fn open_chunk(&self, file_index: u32, index: u64, stored: &[u8]) -> Result<Vec<u8>, String> {
+        let mut rest = stored;
+        if index == 0 {
+            let (header, after_header) = FileHeader::parse(stored)?;
+            if header != self.header(file_index) {
+                return Err("Chunk header doesn't match the file".into());
+            }
+            rest = after_header;
+        }
+        let frame = take(&mut rest, FRAME_SIZE as usize)?;
+        if u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]) as usize != rest.len() {
+            return Err("Chunk length doesn't match its frame".into());
+        }
+        self.decrypt_chunk(file_index, index, rest)
+    }
+
+    // ciphertext | tag
+    pub fn encrypt_chunk(&self, file_index: u32, counter: u64, data: &[u8]) -> Vec<u8> {
+        let nonce = nonce(file_index, counter);
+        let mut encrypted_data = vec![0u8; data.len()];
+        let mut tag = vec![0u8; TAG_SIZE];
+        let mut cipher = AesGcm::new(KeySize256, &self.key, &nonce, &[]);
+        cipher.encrypt(data, &mut encrypted_data, &mut tag);
+        [encrypted_data, tag].concat()
+    }
+
+    pub fn decrypt_chunk(
+        &self,
+        file_index: u32,
+        counter: u64,
+        encrypted_data_with_tag: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        if encrypted_data_with_tag.len() < TAG_SIZE {
+            return Err("Encrypted data is too short".into());
+        }
+        let (encrypted_data, tag) =
+            encrypted_data_with_tag.split_at(encrypted_data_with_tag.len() - TAG_SIZE);
+        let nonce = nonce(file_index, counter);
+        let mut decrypted_data = vec![0u8; encrypted_data.len()];
+        let mut cipher = AesGcm::new(KeySize256, &self.key, &nonce, &[]);
+        if cipher.decrypt(encrypted_data, &mut decrypted_data, tag) {
+            Ok(decrypted_data)
+        } else {
+            Err("Decryption failed".into())
+        }
+    }
+
+    // header | ciphertext | tag, the header lets the name be decrypted on its own
+    pub fn encrypt_name(&self, file_index: u32, name: &str) -> Vec<u8> {
+        [
+            self.header(file_index).to_bytes(),
+            self.encrypt_chunk(file_index, NAME_COUNTER, name.as_bytes()),
+        ]
+        .concat()
+    }
+
+    // expects the header to be split off already, see FileHeader::parse
+    pub fn decrypt_name(&self, file_index: u32, encrypted_name: &[u8]) -> Result<String, String> {
+        let decrypted = self.decrypt_chunk(file_index, NAME_COUNTER, encrypted_name)?;
+        String::from_utf8(decrypted).map_err(|e| e.to_string())
+    }
+
+    // where chunk `index` starts in a file of this session, the first chunk carries the header
+    pub fn chunk_offset(&self, index: u64) -> u64 {
+        self.header(0).chunk_offset(index)
+    }
+}
+
+// a small standalone blob, e.g. an index: header | length | ciphertext | tag, under a key of its own
+pub fn seal_blob(password: &str, kdf: Kdf, data: &[u8]) -> Vec<u8> {
+    SessionKey::new(password, kdf).seal_chunk(0, 0, data)
+}
+
+pub fn open_blob(password: &str, stored: &[u8]) -> Result<Vec<u8>, String> {
+    let (header, _) = FileHeader::parse(stored)?;
+    SessionKey::derive(password, header.kdf, &header.salt).open_chunk(header.file_index, 0, stored)
+}
+
+fn nonce(file_index: u32, counter: u64) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..4].copy_from_slice(&file_index.to_be_bytes());
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(version: u8, kdf: Kdf) -> FileHeader {
+        FileHeader {
+            version,
+            cipher: Cipher::Aes256Gcm,
+            kdf,
+            salt: [7; SALT_SIZE],
+            chunk_size: if version == FORMAT_VERSION { CHUNK_SIZE } else { LEGACY_CHUNK_SIZE },
+            file_index: 42,
+        }
+    }
+
+    #[test]
+    fn header_round_trips_in_every_version() {
+        let headers = [
+            header(VERSION_HKDF, Kdf::Hkdf),
+            header(VERSION_SCRYPT, Kdf::SCRYPT_DEFAULT),
+            header(FORMAT_VERSION, Kdf::Hkdf),
+            header(FORMAT_VERSION, Kdf::SCRYPT_DEFAULT),
+        ];
+        for header in headers {
+            let mut bytes = header.to_bytes();
+            assert_eq!(bytes.len(), header.size());
+            bytes.extend_from_slice(b"rest");
+            let (parsed, rest) = FileHeader::parse(&bytes).unwrap();
+            assert_eq!(parsed, header);
+            assert_eq!(rest, b"rest");
+        }
+    }
+
+    #[test]
+    fn truncated_header_is_rejected() {
+        let bytes = header(FORMAT_VERSION, Kdf::SCRYPT_DEFAULT).to_bytes();
+        for len in 0..bytes.len() {
+            assert!(FileHeader::parse(&bytes[..len]).is_err(), "{} bytes parsed", len);
+        }
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        let mut bytes = header(FORMAT_VERSION, Kdf::Hkdf).to_bytes();
+        bytes[4] = FORMAT_VERSION + 1;
+        assert!(FileHeader::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn out_of_range_scrypt_params_are_rejected() {
+        let costly = [
+            Kdf::Scrypt { log_n: 20, r: 32, p: 16 },
+            Kdf::Scrypt { log_n: 16, r: 8, p: 1 },
+            Kdf::Scrypt { log_n: 15, r: 16, p: 1 },
+            Kdf::Scrypt { log_n: 15, r: 8, p: 2 },
+            Kdf::Scrypt { log_n: 0, r: 8, p: 1 },
+            Kdf::Scrypt { log_n: 14, r: 0, p: 1 },
+            Kdf::Scrypt { log_n: 63, r: u32::MAX, p: 1 },
+            Kdf::Scrypt { log_n: 15, r: u32::MAX, p: 1 },
+            Kdf::Scrypt { log_n: 1, r: 1 << 28, p: 1 },
+        ];
+        for kdf in costly {
+            for version in [VERSION_SCRYPT, FORMAT_VERSION] {
+                let bytes = header(version, kdf).to_bytes();
+                assert!(FileHeader::parse(&bytes).is_err(), "{:?} accepted", kdf);
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_round_trips() {
+        let session = SessionKey::new("password", Kdf::Hkdf);
+        for index in 0..3 {
+            let sealed = session.seal_chunk(3, index, b"some data");
+            assert_eq!(sealed.len() as u64, session.header(3).stored_chunk_len(index, 9));
+            assert_eq!(session.open_chunk(3, index, &sealed).unwrap(), b"some data");
+            // the nonce depends on both indexes
+            assert!(session.open_chunk(4, index, &sealed).is_err());
+            assert!(session.open_chunk(3, index + 1, &sealed).is_err());
+        }
+    }
+
+    #[test]
+    fn tampered_chunk_is_rejected() {
+        let session = SessionKey::new("password", Kdf::Hkdf);
+        for index in 0..2 {
+            let sealed = session.seal_chunk(3, index, b"some data");
+            for position in 0..sealed.len() {
+                let mut tampered = sealed.clone();
+                tampered[position] ^= 1;
+                assert!(
+                    session.open_chunk(3, index, &tampered).is_err(),
+                    "chunk {} tampered at {} opened",
+                    index,
+                    position
+                );
+            }
+            assert!(session.open_chunk(3, index, &sealed[..sealed.len() - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn chunk_offsets_match_the_sealed_file() {
+        let session = SessionKey::new("password", Kdf::Hkdf);
+        let header = session.header(1);
+        // two full chunks and a short last one, as stored one after the other
+        let mut stored = Vec::new();
+        for (index, len) in [(0, CHUNK_SIZE), (1, CHUNK_SIZE), (2, 5)] {
+            assert_eq!(header.chunk_offset(index), stored.len() as u64);
+            assert_eq!(session.chunk_offset(index), stored.len() as u64);
+            let sealed = session.seal_chunk(1, index, &vec![7; len as usize]);
+            assert_eq!(sealed.len() as u64, header.stored_chunk_len(index, len));
+            stored.extend_from_slice(&sealed);
+        }
+        assert_eq!(
+            header.stored_chunk_size(),
+            header.stored_chunk_len(1, CHUNK_SIZE)
+        );
+        // the first chunk also carries the header
+        assert_eq!(
+            header.stored_chunk_len(0, 5),
+            header.stored_chunk_len(1, 5) + header.size() as u64
+        );
+    }
+
+    #[test]
+    fn chunk_under_another_password_is_rejected() {
+        let session = SessionKey::new("password", Kdf::Hkdf);
+        let sealed = session.seal_chunk(0, 0, b"some data");
+        let other = SessionKey::derive("other", Kdf::Hkdf, session.salt());
+        assert!(other.open_chunk(0, 0, &sealed).is_err());
+    }
+}
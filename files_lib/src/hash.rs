@@ -0,0 +1,51 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::collections::HashMap;
+
+// hex sha256 of a single buffer, used for chunks
+pub fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+// incremental sha256, fed chunk by chunk so whole files never have to be in memory
+pub struct FileHasher {
+    hasher: Sha256,
+}
+
+impl FileHasher {
+    pub fn new() -> Self {
+        FileHasher {
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.input(data);
+    }
+
+    pub fn finish(mut self) -> String {
+        self.hasher.result_str()
+    }
+}
+
+impl Default for FileHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// hash over every (file_path, digest) pair, sorted by path so both sides agree on the order
+pub fn root_hash(digests: &HashMap<String, String>) -> String {
+    let mut paths: Vec<&String> = digests.keys().collect();
+    paths.sort();
+    let mut hasher = Sha256::new();
+    for path in paths {
+        hasher.input(path.as_bytes());
+        hasher.input(&[0]);
+        hasher.input(digests[path].as_bytes());
+        hasher.input(b"\n");
+    }
+    hasher.result_str()
+}
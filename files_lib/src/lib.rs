@@ -2,9 +2,57 @@ use kinode_process_lib::vfs::{open_dir, open_file, DirEntry, FileType, VfsAction
 use kinode_process_lib::{println, Request};
 use std::collections::HashMap;
 
+use structs::{CHECKPOINT_FILE, INDEX_FILE, TRANSFER_KEY_FILE};
+
 pub mod encryption;
+pub mod hash;
 pub mod structs;
 
+// random id shared by both nodes to refer to the same transfer
+pub fn new_transfer_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+// secret shared by the two workers of a transfer, so no other process can pose as either
+pub fn new_token() -> String {
+    rand::random::<[u8; 32]>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// joins a path that came from another node (or a user) onto one of our dirs,
+// refusing anything that could point outside of it
+// "a/b", "/a/b" and "a//b/" are all fine, "..", "." and backslashes are not
+pub fn safe_join(base: &str, path: &str) -> anyhow::Result<String> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        if component.is_empty() {
+            continue;
+        }
+        if component == "."
+            || component == ".."
+            || component.contains('\\')
+            || component.contains('\0')
+        {
+            return Err(anyhow::anyhow!("{} escapes {}", path, base));
+        }
+        components.push(component);
+    }
+    if components.is_empty() {
+        return Err(anyhow::anyhow!("{} doesn't name anything within {}", path, base));
+    }
+    Ok(format!("{}/{}", base.trim_end_matches('/'), components.join("/")))
+}
+
+// whether a path within a receiving dir would land on one of the files we keep there ourselves
+pub fn is_bookkeeping(path: &str) -> bool {
+    path.split('/')
+        .find(|component| !component.is_empty())
+        .map(|first| [CHECKPOINT_FILE, TRANSFER_KEY_FILE, INDEX_FILE].contains(&first))
+        .unwrap_or(false)
+}
+
 // outputs file contents
 pub fn read_file(dir: DirEntry) -> anyhow::Result<String> {
     if dir.path.ends_with(".DS_Store") {
@@ -131,3 +179,46 @@ pub fn import_notes(directory: HashMap<String, String>, import_to: &String) -> a
     println!("done importing notes");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_join_keeps_paths_within_base() {
+        assert_eq!(safe_join("/base", "a/b").unwrap(), "/base/a/b");
+        assert_eq!(safe_join("/base/", "/a//b/").unwrap(), "/base/a/b");
+        assert_eq!(safe_join("", "a").unwrap(), "/a");
+        assert_eq!(safe_join("/base", "a..b/.c").unwrap(), "/base/a..b/.c");
+    }
+
+    #[test]
+    fn safe_join_refuses_parent_and_current_dirs() {
+        for path in ["..", "../a", "a/../../b", "a/..", ".", "./a", "a/./b"] {
+            assert!(safe_join("/base", path).is_err(), "{} was joined", path);
+        }
+    }
+
+    #[test]
+    fn safe_join_refuses_backslashes_and_nul() {
+        for path in ["a\\b", "..\\a", "\\", "a/b\0c"] {
+            assert!(safe_join("/base", path).is_err(), "{:?} was joined", path);
+        }
+    }
+
+    #[test]
+    fn safe_join_refuses_empty_paths() {
+        for path in ["", "/", "//"] {
+            assert!(safe_join("/base", path).is_err(), "{:?} was joined", path);
+        }
+    }
+
+    #[test]
+    fn bookkeeping_files_are_recognised_at_the_top_only() {
+        assert!(is_bookkeeping(CHECKPOINT_FILE));
+        assert!(is_bookkeeping(&format!("/{}", TRANSFER_KEY_FILE)));
+        assert!(is_bookkeeping(&format!("/{}/a", INDEX_FILE)));
+        assert!(!is_bookkeeping(&format!("a/{}", CHECKPOINT_FILE)));
+        assert!(!is_bookkeeping("notes.md"));
+    }
+}
@@ -1,5 +1,28 @@
 use kinode_process_lib::Address;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+// chunks in flight between the workers, when the requester doesn't ask for a window
+pub const DEFAULT_WINDOW: u64 = 8;
+pub const MAX_WINDOW: u64 = 64;
+// seconds the sending worker waits for a chunk ack before sending it again
+pub const ACK_TIMEOUT: u64 = 30;
+// attempts at sending a chunk before the transfer fails
+pub const MAX_SENDS: u32 = 5;
+// seconds between the progress reports a worker sends its parent
+pub const PROGRESS_INTERVAL: u64 = 2;
+// seconds a worker goes on without hearing from its peer before giving up on the transfer,
+// it has to cover the sender hashing the whole folder before its manifest
+pub const IDLE_TIMEOUT: u64 = 300;
+// seconds between a worker's checks of the above
+pub const IDLE_CHECK: u64 = 30;
+
+// journal the receiving worker keeps in the receiving dir while a transfer is in progress
+pub const CHECKPOINT_FILE: &str = ".folder_transfer_checkpoint";
+// sender's ephemeral public key, kept next to data encrypted to our public key
+pub const TRANSFER_KEY_FILE: &str = ".folder_transfer_key";
+// encrypted index of original paths, kept in send_to for restoring single files
+pub const INDEX_FILE: &str = ".folder_transfer_index";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum WorkerRequest {
@@ -7,19 +30,170 @@ pub enum WorkerRequest {
         target_worker: Option<Address>,
         sending_dir: String,
         password: Option<String>,
+        // when encrypting to the receiver's public key, our half of the exchange
+        ephemeral_public_key: Option<[u8; 32]>,
+        // max number of chunks sent and not acknowledged yet
+        window: u64,
+        // the receiving side's token for this transfer, presented in the resume handshake
+        token: String,
     },
     InitializeReceiverWorker {
         receive_to_dir: String,
+        // only a worker on this node presenting `token` may send us the transfer
+        peer_node: String,
+        token: String,
+        // transfers with a bigger manifest are rejected
+        max_bytes: Option<u64>,
+        // set to decrypt chunks as they arrive and store the files under their real names
+        decrypt: Option<DecryptWith>,
     },
     Chunk {
         done: bool,
         file_path: String,
+        // position of the chunk within its file, the receiver works out where it goes from it
+        index: u64,
         encrypted: bool,
+        // sha256 of the blob as sent (ciphertext if encrypted)
+        hash: String,
+    },
+    // sender -> receiver, before sending any chunk, asking what is already stored
+    // the receiver only takes manifest and chunks from the worker that made it with the right token
+    ResumeHandshake { token: String },
+    // sender -> receiver, every IDLE_CHECK seconds while the sender reads the folder for the manifest,
+    // so a big folder doesn't look like a sender that went quiet
+    Keepalive,
+    // sender -> receiver, after the resume handshake and before any chunk
+    Manifest(TransferManifest),
+    // parent -> worker, the transfer is off, e.g. the other side failed or it was cancelled
+    // a receiving worker told to `discard` removes what it stored, otherwise it's kept for a later attempt
+    Abort {
+        reason: String,
+        #[serde(default)]
+        discard: bool,
     },
+    // parent -> sending worker, stop sending new chunks until Resume, those in flight still get acked
+    Pause,
+    Resume,
 }
 
-// worker -> main:command_center
+// what a receiving worker decrypts incoming chunks with
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DecryptWith {
+    Password(String),
+    // our secret key, the password is derived from it and the sender's ephemeral public key in the manifest
+    KeyExchange { secret: [u8; 32] },
+}
+
+// everything the sender is going to send, so the receiver can check it up front
+// and verify against it at the end
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferManifest {
+    // file_path as sent in `Chunk` -> entry
+    pub files: HashMap<String, ManifestEntry>,
+    pub total_bytes: u64,
+    pub total_chunks: u64,
+    pub encrypted: bool,
+    // set when encrypted to the receiver's public key, the receiver needs it to derive the password
+    pub ephemeral_public_key: Option<[u8; 32]>,
+    // `files_lib::hash::root_hash` over every file digest
+    pub root_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    // plaintext size
+    pub size: u64,
+    // seconds since epoch, when known
+    pub modified: Option<u64>,
+    // sha256 of the file as the receiver stores it, of its ciphertext when encrypted
+    pub digest: String,
+    pub chunks: u64,
+}
+
+// receiver -> sender
+#[derive(Serialize, Deserialize, Debug)]
+pub enum WorkerResponse {
+    // chunk indexes already stored per file_path, the sender skips these
+    Resume {
+        completed: HashMap<String, FileCheckpoint>,
+    },
+    ManifestAccepted,
+    ManifestRejected {
+        reason: String,
+    },
+    // ack of a `Chunk`, the chunk is identified by the request context
+    ChunkStored,
+    // the sender should send this chunk again
+    ChunkRejected {
+        reason: String,
+    },
+    // the chunk arrived intact but can't be stored, the receiver gave up and the sender should too
+    ChunkRefused {
+        reason: String,
+    },
+}
+
+// what the receiver has stored of a file so far
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FileCheckpoint {
+    pub chunks: BTreeSet<u64>,
+    pub bytes: u64,
+    // digest from the manifest the chunks were sent under
+    pub digest: Option<String>,
+    // path under the receiving dir, when different from the file_path as sent
+    // (decrypted on receive)
+    pub stored_path: Option<String>,
+}
+
+// one line of the checkpoint journal
 #[derive(Serialize, Deserialize, Debug)]
+pub struct CheckpointEntry {
+    pub file_path: String,
+    // (index, length) of a stored chunk
+    pub chunk: Option<(u64, u64)>,
+    // digest of the file from the manifest
+    // a line with neither means the file is to be sent again from scratch
+    #[serde(default)]
+    pub digest: Option<String>,
+    // recorded along with the digest
+    #[serde(default)]
+    pub stored_path: Option<String>,
+}
+
+// worker -> main:command_center
+// every variant but Progress is the last thing a worker sends before it exits
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum WorkerStatus {
+    // sent every PROGRESS_INTERVAL seconds while chunks move, the worker keeps going
+    Progress(TransferProgress),
+    // the worker is alive but has no progress to tell, e.g. while the sender hashes its folder
+    Heartbeat,
     Done,
+    // transfer finished, `files_done` files verified, the `failed` ones are missing,
+    // incomplete or don't match their digest
+    PartiallyDone { files_done: u64, failed: Vec<String> },
+    // the receiver refused the manifest, nothing was sent
+    Rejected { reason: String },
+    // `file` as sent, when the failure is down to one file
+    Failed { reason: String, file: Option<String> },
+    // nothing came from the other side for `secs` seconds, reported by a worker or its parent's watchdog
+    TimedOut { secs: u64 },
+    // never sent by a worker, what the parent records when `by` cancelled the transfer
+    Cancelled { by: String },
+}
+
+// a worker's view of how far its transfer got, counting what earlier attempts stored
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TransferProgress {
+    pub files_done: u64,
+    pub files_total: u64,
+    // plaintext bytes
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    // file_path of the last chunk moved, as sent, so encrypted names stay encrypted
+    pub current_file: Option<String>,
+    // bytes per second over this attempt
+    pub throughput: u64,
+    // seconds left at that rate, None until anything moved
+    pub eta: Option<u64>,
 }
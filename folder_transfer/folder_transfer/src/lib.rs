@@ -1,13 +1,21 @@
 use kinode_process_lib::{
-    await_message, call_init, our_capabilities, println, spawn,
+    await_message, call_init, get_state, our_capabilities, println, set_state, spawn, timer,
     vfs::{create_drive, DirEntry, FileType, VfsAction, VfsRequest, open_file, SeekFrom, open_dir, create_file},
-    Address, OnExit, Request, 
+    Address, OnExit, Request, Response,
 };
 
-use files_lib::encryption::{decrypt_data, ENCRYPTED_CHUNK_SIZE};
-use files_lib::structs::{WorkerRequest, WorkerStatus};
-use files_lib::{read_nested_dir_light};
+use files_lib::encryption::{
+    decrypt_data, generate_keypair, open_blob, seal_blob, shared_password, FileHeader, Kdf,
+    SessionKey, ENCRYPTED_CHUNK_SIZE, FRAME_SIZE, PUBLIC_KEY_SIZE, SALT_SIZE,
+};
+use files_lib::structs::{
+    DecryptWith, TransferProgress, WorkerRequest, WorkerStatus, CHECKPOINT_FILE, DEFAULT_WINDOW, INDEX_FILE,
+    TRANSFER_KEY_FILE,
+};
+use files_lib::{new_token, new_transfer_id, read_dir, read_nested_dir_light, safe_join};
+use std::collections::{HashMap, VecDeque};
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use base64::{engine::general_purpose, Engine as _};
 
@@ -27,21 +35,439 @@ pub enum FolderTransfer {
         node_id: String,
         folder: String,
         encrypt: bool,
+        // name of the key to encrypt with, both nodes must have a key under this name
+        // without one, the sender encrypts to our public key
+        #[serde(default)]
+        key: Option<String>,
+        // chunks in flight at once, defaults to DEFAULT_WINDOW
+        #[serde(default)]
+        window: Option<u64>,
+        // decrypt chunks as they arrive, so the folder lands in send_to under its real names
+        // instead of needing a DecryptFolder afterwards
+        #[serde(default)]
+        decrypt_on_receive: bool,
+        // what to do when send_to/<node_id>/<folder> already holds a received folder,
+        // defaults to the policy set with SetConflictPolicy
+        #[serde(default)]
+        on_conflict: Option<ConflictPolicy>,
     },
     // message that is sent to the target node, requesting them to send the folder
     RequestFolderMessage {
+        transfer_id: String,
         worker_address: Address,
         folder: String,
         encrypt: bool,
+        // key name only, the password itself never leaves the node
+        key: Option<String>,
+        // requester's public key, used when no key name is given
+        #[serde(default)]
+        public_key: Option<[u8; PUBLIC_KEY_SIZE]>,
+        window: Option<u64>,
+        // our sending worker presents it to the receiving worker, which takes a transfer from no one else
+        token: String,
+    },
+    // answer to a RequestFolderMessage, sent again once a pending request is approved or denied
+    RequestFolderResponse {
+        transfer_id: String,
+        result: RequestFolderResult,
+    },
+    // who may pull which of our folders, `node` being a node id or "*" for any node
+    SetFolderAccess {
+        folder: String,
+        node: String,
+        access: Access,
+    },
+    RemoveFolderAccess {
+        folder: String,
+        node: String,
+    },
+    ListFolderAccess,
+    // action that offers one of our folders to the target node, which may accept or decline it
+    SendFolderAction {
+        node_id: String,
+        folder: String,
+        encrypt: bool,
+        // as in RequestFolderAction, without one we encrypt to the target node's public key
+        #[serde(default)]
+        key: Option<String>,
+        #[serde(default)]
+        window: Option<u64>,
+    },
+    // message that is sent to the target node, offering it the folder
+    SendFolderOffer {
+        transfer_id: String,
+        folder: String,
+        encrypt: bool,
+        key: Option<String>,
+    },
+    // the offered node's answers, with the worker we are to send to
+    SendFolderAccepted {
+        transfer_id: String,
+        worker_address: Address,
+        public_key: Option<[u8; PUBLIC_KEY_SIZE]>,
+        token: String,
+    },
+    SendFolderDeclined {
+        transfer_id: String,
+        reason: String,
+    },
+    // which nodes may send us folders without us asking for them
+    SetPushPolicy {
+        policy: PushPolicy,
+    },
+    // requests for our folders and offers of folders wait here until approved or denied
+    ListPendingRequests,
+    // `id` being the transfer id ListPendingRequests prints
+    ApproveRequest {
+        id: String,
+    },
+    DenyRequest {
+        id: String,
+    },
+    // lets requests (`pulls`) and offers (`pushes`) from `node`, or "*" for any node, go ahead without approval
+    SetAutoApprove {
+        node: String,
+        pulls: bool,
+        pushes: bool,
+    },
+    // decrypts `folder` of send_to, e.g. "node.os/some_folder", into decrypt_to/`destination`
+    // without a key name, decrypts what was encrypted to our public key
+    DecryptFolder {
+        folder: String,
+        destination: String,
+        #[serde(default)]
+        key: Option<String>,
+        // replace what is already in the destination, refused otherwise
+        #[serde(default)]
+        overwrite: bool,
+    },
+    // decrypts only the given files or folders of `folder` in send_to into decrypt_to/`destination`,
+    // paths within the received folder, e.g. "notes/todo.md", `folder` being e.g. "node.os/some_folder"
+    DecryptPaths {
+        folder: String,
+        paths: Vec<String>,
+        destination: String,
+        #[serde(default)]
+        key: Option<String>,
+        // replace files already in the destination, refused otherwise
+        #[serde(default)]
+        overwrite: bool,
+    },
+    // stores a password under `name`, for encrypting and decrypting transfers
+    AddKey {
+        name: String,
+        password: String,
     },
-    DecryptFolder,
+    RemoveKey {
+        name: String,
+    },
+    ListKeys,
+    // incoming transfers bigger than this are rejected by our receiving worker, None for no limit
+    SetTransferLimit {
+        max_bytes: Option<u64>,
+    },
+    SetConflictPolicy {
+        policy: ConflictPolicy,
+    },
+    // prints our live transfers, and answers with them
+    ListTransfers,
+    // prints the latest progress report of transfer `id`, or how it ended,
+    // and answers with a TransferStatusReply
+    GetTransferStatus {
+        id: String,
+    },
+    // prints how the last HISTORY_SIZE transfers ended, and answers with them
+    ListOutcomes,
+    // sent to the other node of a transfer once our worker is done with it, however it went
+    TransferOutcome {
+        transfer_id: String,
+        status: WorkerStatus,
+    },
+    // stops transfer `id` on both nodes, or an offer or request not answered yet
+    // `discard` removes what the receiving worker stored, otherwise it's kept so the transfer can be run again
+    CancelTransfer {
+        id: String,
+        #[serde(default)]
+        discard: bool,
+    },
+    // the sending worker stops sending new chunks until ResumeTransfer, whichever node asks
+    PauseTransfer {
+        id: String,
+    },
+    ResumeTransfer {
+        id: String,
+    },
+    // a Cancel/Pause/ResumeTransfer passed on to the other node of the transfer
+    TransferControl {
+        transfer_id: String,
+        action: TransferAction,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TransferAction {
+    Cancel { discard: bool },
+    Pause,
+    Resume,
+}
+
+// who ended a transfer, which decides who else has to hear about it
+enum EndedBy {
+    // our worker, which already exited, the other node is told how it went
+    Worker,
+    // the other node, or a request that never got going, our worker is stopped
+    Peer { discard: bool },
+    // a CancelTransfer of ours, our worker is stopped and the other node told to stop its own
+    Us { discard: bool },
+    // our watchdog, our worker is stopped and the other node told how it went
+    Watchdog,
+}
+
+// transfers whose outcome we keep
+const HISTORY_SIZE: usize = 100;
+// seconds a requested or running transfer may go without any sign of life before the watchdog ends it
+const STALL_TIMEOUT: u64 = 600;
+// seconds we wait for the other node to approve one of our requests, its receiving worker waits all along
+const APPROVAL_TIMEOUT: u64 = 24 * 60 * 60;
+// seconds between watchdog rounds
+const WATCHDOG_INTERVAL: u64 = 60;
+
+// answer to GetTransferStatus
+#[derive(Serialize, Deserialize, Debug)]
+pub enum TransferStatusReply {
+    Live(Transfer),
+    Finished(Outcome),
+    Unknown,
+}
+
+// what became of a RequestFolderMessage
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RequestFolderResult {
+    // the sending worker is on its way
+    Accepted { summary: FolderSummary },
+    // waiting for the node to ApproveRequest or DenyRequest it
+    Pending,
+    NotFound,
+    AccessDenied { reason: String },
+    // the same transfer, or the same folder to the same node, is already going
+    Busy { reason: String },
+    Failed { reason: String },
+}
+
+// what a requested folder holds, as its sender sees it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FolderSummary {
+    pub files: u64,
+    // plaintext bytes, 0 in summaries from before it was sent
+    #[serde(default)]
+    pub bytes: u64,
+}
+
+// what happens to a folder we received earlier when the same folder comes in again from the same node
+// an unfinished transfer in its place is always resumed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum ConflictPolicy {
+    // the old folder is removed
+    Overwrite,
+    // the new one goes next to it, as "folder (1)", "folder (2)", ...
+    #[default]
+    Rename,
+    // received files replace the old ones with the same path, the rest is kept
+    Merge,
+    // the transfer is refused
+    Reject,
+}
+
+// what a node may do with one of our folders
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Denied,
+}
+
+// who may push folders to us
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum PushPolicy {
+    #[default]
+    RejectAll,
+    AcceptAll,
+    AcceptFrom { nodes: Vec<String> },
+}
+
+impl PushPolicy {
+    fn accepts(&self, node: &str) -> bool {
+        match self {
+            PushPolicy::RejectAll => false,
+            PushPolicy::AcceptAll => true,
+            PushPolicy::AcceptFrom { nodes } => nodes.iter().any(|n| n == node),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum TransferStatus {
+    // we asked for the folder and haven't heard back
+    #[default]
+    Requested,
+    // the node holds our request until it approves it
+    AwaitingApproval,
+    Running,
+    Paused,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TransferRole {
+    Sender,
+    Receiver,
+}
+
+// one live transfer, and the worker handling our side of it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transfer {
+    pub role: TransferRole,
+    pub worker_address: Address,
+    pub peer_node: String,
+    pub folder: String,
+    pub encrypt: bool,
+    pub key: Option<String>,
+    // where our receiving worker stores the folder, None when sending
+    #[serde(default)]
+    pub receive_dir: Option<String>,
+    #[serde(default)]
+    pub status: TransferStatus,
+    // what the sender said it is sending, for folders we requested
+    #[serde(default)]
+    pub summary: Option<FolderSummary>,
+    // latest report of our worker
+    #[serde(default)]
+    pub progress: Option<TransferProgress>,
+    // unix time of the last sign of life, a status change or a progress report
+    #[serde(default)]
+    pub last_activity: u64,
+    // how the other node's worker ended, if it did before ours
+    #[serde(default)]
+    pub peer_status: Option<WorkerStatus>,
+}
+
+// how a transfer ended
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Outcome {
+    pub transfer_id: String,
+    pub role: TransferRole,
+    pub peer_node: String,
+    pub folder: String,
+    // as our worker reported it, or as we ended the transfer
+    pub status: WorkerStatus,
+    // as the other node reported it
+    pub peer_status: Option<WorkerStatus>,
+}
+
+// a request or offer from another node, until we approve or deny it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PendingRequest {
+    // the node wants one of our folders
+    Pull {
+        peer_node: String,
+        worker_address: Address,
+        folder: String,
+        encrypt: bool,
+        key: Option<String>,
+        public_key: Option<[u8; PUBLIC_KEY_SIZE]>,
+        window: Option<u64>,
+        token: String,
+    },
+    // the node wants to send us one of its folders
+    Push {
+        peer_node: String,
+        folder: String,
+        encrypt: bool,
+        key: Option<String>,
+    },
+}
+
+impl PendingRequest {
+    fn peer_node(&self) -> &str {
+        match self {
+            PendingRequest::Pull { peer_node, .. } | PendingRequest::Push { peer_node, .. } => peer_node,
+        }
+    }
+}
+
+// what a node may do without waiting for approval
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct AutoApprove {
+    pub pulls: bool,
+    pub pushes: bool,
+}
+
+// a folder we offered to a node, until it answers or APPROVAL_TIMEOUT runs out
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Offer {
+    pub peer_node: String,
+    pub folder: String,
+    pub encrypt: bool,
+    pub key: Option<String>,
+    pub window: Option<u64>,
+    // unix time the offer was made
+    #[serde(default)]
+    pub created: u64,
+}
+
+// which folder goes between us and `peer_node`, and how, as the pull and push paths agreed on it
+#[derive(Debug, Clone, Default)]
+struct TransferSpec {
+    peer_node: String,
+    folder: String,
+    encrypt: bool,
+    // key name, both nodes hold a password under it
+    key: Option<String>,
+    // the receiver's, when encrypting to it instead of a key, only the sender uses it
+    public_key: Option<[u8; PUBLIC_KEY_SIZE]>,
+    window: Option<u64>,
+    // only the receiver uses these two
+    decrypt_on_receive: bool,
+    on_conflict: Option<ConflictPolicy>,
+}
+
+// persisted across restarts
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct State {
+    pub transfers: HashMap<String, Transfer>,
+    // how the last HISTORY_SIZE transfers ended, oldest first
+    pub history: VecDeque<Outcome>,
+    // incoming transfers bigger than this are rejected, None for no limit
+    pub max_transfer_bytes: Option<u64>,
+    // key name -> password
+    pub keys: HashMap<String, String>,
+    // our x25519 (secret, public) keypair, the public half is sent along with our requests
+    pub keypair: Option<([u8; 32], [u8; PUBLIC_KEY_SIZE])>,
+    // applied to incoming folders when the request doesn't pick one
+    pub conflict_policy: ConflictPolicy,
+    pub push_policy: PushPolicy,
+    // folder -> node (or "*") -> access, folders nobody was given access to can't be pulled
+    pub folder_access: HashMap<String, HashMap<String, Access>>,
+    // transfer id -> folder we offered and haven't heard back about
+    pub offers: HashMap<String, Offer>,
+    // transfer id -> request or offer waiting on ApproveRequest or DenyRequest
+    pub pending: HashMap<String, PendingRequest>,
+    // node (or "*") -> what goes ahead without approval, nothing does by default
+    pub auto_approve: HashMap<String, AutoApprove>,
+}
+
+fn load_state() -> State {
+    get_state()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn save_state(state: &State) -> anyhow::Result<()> {
+    set_state(&serde_json::to_vec(state)?);
+    Ok(())
 }
 
 // spawns a worker process for folder transfer (whether it will be for receiving or sending)
-fn initialize_worker(
-    our: Address,
-    current_worker_address: &mut Option<Address>,
-) -> anyhow::Result<()> {
+fn initialize_worker(our: &Address) -> anyhow::Result<Address> {
     let our_worker = spawn(
         None,
         &format!("{}/pkg/worker.wasm", our.package_id()),
@@ -51,110 +477,545 @@ fn initialize_worker(
         false,
     )?;
 
-    // temporarily stores worker address while the worker is alive
-    *current_worker_address = Some(Address {
+    Ok(Address {
         node: our.node.clone(),
-        process: our_worker.clone(),
-    });
-    Ok(())
+        process: our_worker,
+    })
 }
 
 fn handle_message(
     our: &Address,
-    current_worker_address: &mut Option<Address>,
+    state: &mut State,
     send_from_path: String,
     send_to_path: String,
     decrypt_to_path: String
 ) -> anyhow::Result<()> {
-    let message = await_message()?;
+    let message = match await_message() {
+        Ok(message) => message,
+        // a node we asked for a folder never answered
+        Err(send_error) => {
+            if let Ok(FolderTransfer::RequestFolderMessage { transfer_id, .. }) =
+                serde_json::from_slice(send_error.message().body())
+            {
+                if state.transfers.contains_key(&transfer_id) {
+                    let reason = format!(
+                        "no answer from {}: {:?}",
+                        send_error.target().node(),
+                        send_error.kind()
+                    );
+                    return finish(our, state, &transfer_id, WorkerStatus::Failed { reason, file: None }, EndedBy::Peer { discard: false });
+                }
+            }
+            return Err(send_error.into());
+        }
+    };
+
+    if !message.is_request() && message.source() == &Address::new(&our.node, ("timer", "distro", "sys")) {
+        timer::set_timer(WATCHDOG_INTERVAL * 1000, None);
+        return watchdog(our, state);
+    }
 
     if let Ok(request) = serde_json::from_slice::<FolderTransfer>(message.body()) {
+        // other nodes may only ask us for folders or offer us theirs, everything else is a local command
+        if message.source().node != our.node
+            && !matches!(
+                request,
+                FolderTransfer::RequestFolderMessage { .. }
+                    | FolderTransfer::RequestFolderResponse { .. }
+                    | FolderTransfer::SendFolderOffer { .. }
+                    | FolderTransfer::SendFolderAccepted { .. }
+                    | FolderTransfer::SendFolderDeclined { .. }
+                    | FolderTransfer::TransferOutcome { .. }
+                    | FolderTransfer::TransferControl { .. }
+            )
+        {
+            return Err(anyhow::anyhow!(
+                "{} sent a command only accepted from our node",
+                message.source().node()
+            ));
+        }
+
         match request {
             // sending request to target node
             FolderTransfer::RequestFolderAction {
                 node_id,
                 folder,
                 encrypt,
+                key,
+                window,
+                decrypt_on_receive,
+                on_conflict,
             } => {
                 println!("RequestFolderAction: node_id: {}", node_id);
 
-                // spin up worker process
-                initialize_worker(our.clone(), current_worker_address)?;
-
-                println!("send_to_path: {}", send_to_path[1..].to_string());
-                // start receiving data on the worker
-                let _worker_request = Request::new()
-                    .body(serde_json::to_vec(
-                        &WorkerRequest::InitializeReceiverWorker {
-                            receive_to_dir: send_to_path[1..].to_string(),
-                        },
-                    )?)
-                    .target(&current_worker_address.clone().unwrap())
-                    .send()?;
+                let transfer_id = new_transfer_id();
+                let spec = TransferSpec {
+                    peer_node: node_id.clone(),
+                    folder: folder.clone(),
+                    encrypt,
+                    key: key.clone(),
+                    window,
+                    decrypt_on_receive,
+                    on_conflict,
+                    ..Default::default()
+                };
+                let (worker_address, public_key, token) =
+                    start_receiving(our, state, &send_to_path, &transfer_id, spec)?;
+                let key = if encrypt { key } else { None };
 
                 // send request to target node
                 let request_folder_message =
                     serde_json::to_vec(&FolderTransfer::RequestFolderMessage {
-                        worker_address: current_worker_address.clone().unwrap(),
+                        transfer_id: transfer_id.clone(),
+                        worker_address,
                         folder,
                         encrypt,
+                        key,
+                        public_key,
+                        window,
+                        token,
                     })?;
-                let _request = Request::to(Address::new(node_id.clone(), our.process.clone()))
-                    .expects_response(5)
+                // the other node lists the folder, and may start its worker, before answering
+                Request::to(Address::new(node_id, our.process.clone()))
+                    .expects_response(30)
                     .body(request_folder_message)
                     .send()?;
             }
             // received request for folder transfer, sending folder
             FolderTransfer::RequestFolderMessage {
+                transfer_id,
                 worker_address,
                 folder,
                 encrypt,
+                key,
+                public_key,
+                window,
+                token,
             } => {
                 println!("RequestFolderMessage");
-
-                // spin up worker process
-                initialize_worker(our.clone(), current_worker_address)?;
-
-                let sending_dir = format!("{}/{}", send_from_path, folder);
-                println!("send_from_path: {}", sending_dir[1..].to_string());
-                // start sending data from worker
-                let _worker_request = Request::new()
-                    .body(serde_json::to_vec(
-                        &WorkerRequest::InitializeSenderWorker {
-                            target_worker: Some(worker_address.clone()),
-                            sending_dir: sending_dir[1..].to_string(),
-                            password: if encrypt {
-                                Some("some_password".to_string())
-                            } else {
-                                None
-                            },
+                let peer_node = message.source().node().to_string();
+                // not worth holding for approval, start_sending would refuse it anyway
+                if worker_address.node() != peer_node {
+                    let reply = FolderTransfer::RequestFolderResponse {
+                        transfer_id,
+                        result: RequestFolderResult::AccessDenied {
+                            reason: format!("the receiving worker must run on {}", peer_node),
                         },
-                    )?)
-                    .target(&current_worker_address.clone().unwrap())
-                    .send()?;
+                    };
+                    Response::new().body(serde_json::to_vec(&reply)?).send()?;
+                    return Ok(());
+                }
+                let pending = PendingRequest::Pull {
+                    peer_node: peer_node.clone(),
+                    worker_address,
+                    folder: folder.clone(),
+                    encrypt,
+                    key,
+                    public_key,
+                    window,
+                    token,
+                };
+                let reply = if auto_approve(state, &peer_node).pulls {
+                    approve(our, state, &send_from_path, &send_to_path, transfer_id, pending)?
+                } else {
+                    let result = match check_pull(state, &send_from_path, &transfer_id, &folder, &peer_node) {
+                        Ok(_) => {
+                            hold(state, transfer_id.clone(), pending);
+                            RequestFolderResult::Pending
+                        }
+                        Err(result) => result,
+                    };
+                    FolderTransfer::RequestFolderResponse {
+                        transfer_id,
+                        result,
+                    }
+                };
+                Response::new().body(serde_json::to_vec(&reply)?).send()?;
             }
-            // decrypts content of "send_to" directory, and puts it into "decrypted"
-            FolderTransfer::DecryptFolder => {
-                println!("DecryptFolder");
-
-                // /folder_transfer:astronaut.os/send_to
-                // this is the dir where we transfered the folder in an encrypted form
-                let dir_entry: DirEntry = DirEntry {
-                    path: send_to_path.to_string(),
-                    file_type: FileType::Directory,
+            // how the node we asked for a folder answered
+            FolderTransfer::RequestFolderResponse { transfer_id, result } => {
+                let Some(transfer) = state
+                    .transfers
+                    .get_mut(&transfer_id)
+                    .filter(|transfer| transfer.peer_node == message.source().node())
+                else {
+                    return Err(anyhow::anyhow!("no transfer {} from {}", transfer_id, message.source().node()));
                 };
+                match result {
+                    RequestFolderResult::Accepted { summary } => {
+                        println!(
+                            "transfer {}: {} is sending {}: {} files, {} bytes",
+                            transfer_id, transfer.peer_node, transfer.folder, summary.files, summary.bytes
+                        );
+                        transfer.status = TransferStatus::Running;
+                        transfer.summary = Some(summary);
+                        transfer.last_activity = now();
+                    }
+                    RequestFolderResult::Pending => {
+                        println!(
+                            "transfer {}: waiting for {} to approve the request",
+                            transfer_id, transfer.peer_node
+                        );
+                        transfer.status = TransferStatus::AwaitingApproval;
+                        transfer.last_activity = now();
+                    }
+                    refused => {
+                        let reason = format!("request refused: {:?}", refused);
+                        return finish(our, state, &transfer_id, WorkerStatus::Rejected { reason }, EndedBy::Peer { discard: false });
+                    }
+                }
+            }
+            FolderTransfer::SetFolderAccess {
+                folder,
+                node,
+                access,
+            } => {
+                println!("folder access: {} {} {:?}", folder, node, access);
+                state
+                    .folder_access
+                    .entry(folder.trim_matches('/').to_string())
+                    .or_default()
+                    .insert(node, access);
+            }
+            FolderTransfer::RemoveFolderAccess { folder, node } => {
+                let folder = folder.trim_matches('/').to_string();
+                if let Some(nodes) = state.folder_access.get_mut(&folder) {
+                    nodes.remove(&node);
+                    if nodes.is_empty() {
+                        state.folder_access.remove(&folder);
+                    }
+                }
+            }
+            FolderTransfer::ListFolderAccess => {
+                for (folder, nodes) in state.folder_access.iter() {
+                    println!("{}: {:?}", folder, nodes);
+                }
+            }
+            // offering a folder of ours to the target node
+            FolderTransfer::SendFolderAction {
+                node_id,
+                folder,
+                encrypt,
+                key,
+                window,
+            } => {
+                println!("SendFolderAction: node_id: {}", node_id);
+                if let Some(key) = &key {
+                    if encrypt && !state.keys.contains_key(key) {
+                        return Err(anyhow::anyhow!("no key named {}", key));
+                    }
+                }
+                let key = if encrypt { key } else { None };
 
-                // remove and re-create decrypt_to so it's empty
-                let request: VfsRequest = VfsRequest {
-                    path: decrypt_to_path.to_string(),
-                    action: VfsAction::RemoveDirAll,
+                let transfer_id = new_transfer_id();
+                Request::to(Address::new(node_id.clone(), our.process.clone()))
+                    .body(serde_json::to_vec(&FolderTransfer::SendFolderOffer {
+                        transfer_id: transfer_id.clone(),
+                        folder: folder.clone(),
+                        encrypt,
+                        key: key.clone(),
+                    })?)
+                    .send()?;
+
+                println!("transfer {}: offering {} to {}", transfer_id, folder, node_id);
+                state.offers.insert(
+                    transfer_id,
+                    Offer {
+                        peer_node: node_id,
+                        folder,
+                        encrypt,
+                        key,
+                        window,
+                        created: now(),
+                    },
+                );
+            }
+            // a node offers us a folder
+            FolderTransfer::SendFolderOffer {
+                transfer_id,
+                folder,
+                encrypt,
+                key,
+            } => {
+                let peer_node = message.source().node().to_string();
+                println!("SendFolderOffer: {} from {}", folder, peer_node);
+
+                let allowed = if !state.push_policy.accepts(&peer_node) {
+                    Err(format!("{} doesn't accept folders from {}", our.node, peer_node))
+                } else {
+                    check_unseen(state, &transfer_id)
                 };
-                let _message = Request::new()
-                    .target(("our", "vfs", "distro", "sys"))
-                    .body(serde_json::to_vec(&request)?)
-                    .send_and_await_response(5)?;
+                if let Err(reason) = allowed {
+                    println!("transfer {}: declined: {}", transfer_id, reason);
+                    let reply = FolderTransfer::SendFolderDeclined { transfer_id, reason };
+                    return reply_to(our, &peer_node, &reply);
+                }
+                let pending = PendingRequest::Push {
+                    peer_node: peer_node.clone(),
+                    folder,
+                    encrypt,
+                    key,
+                };
+                if auto_approve(state, &peer_node).pushes {
+                    let reply = approve(our, state, &send_from_path, &send_to_path, transfer_id, pending)?;
+                    reply_to(our, &peer_node, &reply)?;
+                } else {
+                    hold(state, transfer_id, pending);
+                }
+            }
+            // the node we offered a folder to is ready to receive it
+            FolderTransfer::SendFolderAccepted {
+                transfer_id,
+                worker_address,
+                public_key,
+                token,
+            } => {
+                let Some(offer) = take_offer(state, &transfer_id, message.source().node()) else {
+                    return Err(anyhow::anyhow!("no offer {} to {}", transfer_id, message.source().node()));
+                };
+                let spec = TransferSpec {
+                    peer_node: offer.peer_node,
+                    folder: offer.folder,
+                    encrypt: offer.encrypt,
+                    key: offer.key,
+                    public_key,
+                    window: offer.window,
+                    ..Default::default()
+                };
+                start_sending(our, state, &send_from_path, transfer_id, spec, worker_address, token)?;
+            }
+            FolderTransfer::SendFolderDeclined { transfer_id, reason } => {
+                if let Some(offer) = take_offer(state, &transfer_id, message.source().node()) {
+                    println!(
+                        "transfer {}: {} declined {}: {}",
+                        transfer_id, offer.peer_node, offer.folder, reason
+                    );
+                }
+            }
+            FolderTransfer::SetPushPolicy { policy } => {
+                println!("push policy: {:?}", policy);
+                state.push_policy = policy;
+            }
+            FolderTransfer::ListPendingRequests => {
+                for (id, pending) in state.pending.iter() {
+                    println!("{}: {:?}", id, pending);
+                }
+            }
+            FolderTransfer::ApproveRequest { id } => {
+                let Some(pending) = state.pending.remove(&id) else {
+                    return Err(anyhow::anyhow!("no pending request {}", id));
+                };
+                let peer_node = pending.peer_node().to_string();
+                let reply = approve(our, state, &send_from_path, &send_to_path, id, pending)?;
+                reply_to(our, &peer_node, &reply)?;
+            }
+            FolderTransfer::DenyRequest { id } => {
+                let Some(pending) = state.pending.remove(&id) else {
+                    return Err(anyhow::anyhow!("no pending request {}", id));
+                };
+                let peer_node = pending.peer_node().to_string();
+                let reply = denial(id, pending, format!("{} denied the request", our.node));
+                reply_to(our, &peer_node, &reply)?;
+            }
+            FolderTransfer::SetAutoApprove { node, pulls, pushes } => {
+                println!("auto approve: {} pulls: {} pushes: {}", node, pulls, pushes);
+                if pulls || pushes {
+                    state.auto_approve.insert(node, AutoApprove { pulls, pushes });
+                } else {
+                    state.auto_approve.remove(&node);
+                }
+            }
+            FolderTransfer::ListTransfers => {
+                for (transfer_id, transfer) in state.transfers.iter() {
+                    println!(
+                        "{}: {:?} {} {} {:?}",
+                        transfer_id, transfer.role, transfer.peer_node, transfer.folder, transfer.status
+                    );
+                }
+                Response::new().body(serde_json::to_vec(&state.transfers)?).send()?;
+            }
+            FolderTransfer::GetTransferStatus { id } => {
+                let transfer = state.transfers.get(&id);
+                match transfer.and_then(|transfer| transfer.progress.as_ref()) {
+                    Some(progress) => println!(
+                        "transfer {}: {}/{} files, {}/{} bytes, {} bytes/s, eta {:?}s, at {:?}",
+                        id,
+                        progress.files_done,
+                        progress.files_total,
+                        progress.bytes_done,
+                        progress.bytes_total,
+                        progress.throughput,
+                        progress.eta,
+                        progress.current_file
+                    ),
+                    None => println!("transfer {}: {:?}", id, transfer.map(|transfer| &transfer.status)),
+                }
+                let reply = match transfer {
+                    Some(transfer) => TransferStatusReply::Live(transfer.clone()),
+                    None => match state.history.iter().find(|outcome| outcome.transfer_id == id) {
+                        Some(outcome) => {
+                            println!("transfer {}: ended: {:?}, {} side: {:?}", id, outcome.status, outcome.peer_node, outcome.peer_status);
+                            TransferStatusReply::Finished(outcome.clone())
+                        }
+                        None => TransferStatusReply::Unknown,
+                    },
+                };
+                Response::new().body(serde_json::to_vec(&reply)?).send()?;
+            }
+            FolderTransfer::ListOutcomes => {
+                for outcome in state.history.iter() {
+                    println!(
+                        "{}: {:?} {} {} {:?}, {} side: {:?}",
+                        outcome.transfer_id,
+                        outcome.role,
+                        outcome.peer_node,
+                        outcome.folder,
+                        outcome.status,
+                        outcome.peer_node,
+                        outcome.peer_status
+                    );
+                }
+                Response::new().body(serde_json::to_vec(&state.history)?).send()?;
+            }
+            FolderTransfer::CancelTransfer { id, discard } => {
+                if state.transfers.contains_key(&id) {
+                    let status = WorkerStatus::Cancelled { by: our.node.clone() };
+                    return finish(our, state, &id, status, EndedBy::Us { discard });
+                }
+                // nothing started yet, the other node only has to forget about it
+                let peer_node = match (state.offers.remove(&id), state.pending.remove(&id)) {
+                    (Some(offer), _) => offer.peer_node,
+                    (None, Some(pending)) => pending.peer_node().to_string(),
+                    (None, None) => return Err(anyhow::anyhow!("no transfer {}", id)),
+                };
+                println!("transfer {}: cancelled", id);
+                let control = FolderTransfer::TransferControl {
+                    transfer_id: id,
+                    action: TransferAction::Cancel { discard },
+                };
+                reply_to(our, &peer_node, &control)?;
+            }
+            FolderTransfer::PauseTransfer { id } => {
+                control_transfer(our, state, &id, TransferAction::Pause, true)?;
+            }
+            FolderTransfer::ResumeTransfer { id } => {
+                control_transfer(our, state, &id, TransferAction::Resume, true)?;
+            }
+            // the other node cancelled, paused or resumed a transfer of ours
+            FolderTransfer::TransferControl { transfer_id, action } => {
+                let peer_node = message.source().node().to_string();
+                let ours = state
+                    .transfers
+                    .get(&transfer_id)
+                    .map(|transfer| transfer.peer_node == peer_node);
+                match (ours, action) {
+                    (Some(true), TransferAction::Cancel { discard }) => {
+                        let status = WorkerStatus::Cancelled { by: peer_node };
+                        return finish(our, state, &transfer_id, status, EndedBy::Peer { discard });
+                    }
+                    (Some(true), action) => {
+                        control_transfer(our, state, &transfer_id, action, false)?;
+                    }
+                    // a request or offer it made that we haven't answered
+                    (None, TransferAction::Cancel { .. }) => {
+                        let from_peer = state
+                            .pending
+                            .get(&transfer_id)
+                            .map(|pending| pending.peer_node() == peer_node)
+                            .unwrap_or(false);
+                        if from_peer {
+                            state.pending.remove(&transfer_id);
+                            println!("transfer {}: {} withdrew it", transfer_id, peer_node);
+                        }
+                    }
+                    _ => {
+                        return Err(anyhow::anyhow!("no transfer {} with {}", transfer_id, peer_node));
+                    }
+                }
+            }
+            // the other node's worker is done with a transfer of ours
+            FolderTransfer::TransferOutcome { transfer_id, status } => {
+                let peer_node = message.source().node();
+                if let Some(transfer) = state
+                    .transfers
+                    .get_mut(&transfer_id)
+                    .filter(|transfer| transfer.peer_node == peer_node)
+                {
+                    println!("transfer {}: {} reports {:?}", transfer_id, peer_node, status);
+                    transfer.peer_status = Some(status.clone());
+                    transfer.last_activity = now();
+                    // a sender that is done leaves our receiving worker to verify and finish on its own,
+                    // anything else means nothing more is coming
+                    if !matches!(status, WorkerStatus::Done) {
+                        let reason = format!("{} side ended: {:?}", peer_node, status);
+                        return finish(our, state, &transfer_id, WorkerStatus::Failed { reason, file: None }, EndedBy::Peer { discard: false });
+                    }
+                } else if let Some(outcome) = state
+                    .history
+                    .iter_mut()
+                    .find(|outcome| outcome.transfer_id == transfer_id && outcome.peer_node == peer_node)
+                {
+                    outcome.peer_status = Some(status);
+                }
+            }
+            FolderTransfer::SetConflictPolicy { policy } => {
+                println!("conflict policy: {:?}", policy);
+                state.conflict_policy = policy;
+            }
+            FolderTransfer::SetTransferLimit { max_bytes } => {
+                println!("transfer limit: {:?} bytes", max_bytes);
+                state.max_transfer_bytes = max_bytes;
+            }
+            FolderTransfer::AddKey { name, password } => {
+                println!("added key {}", name);
+                state.keys.insert(name, password);
+            }
+            FolderTransfer::RemoveKey { name } => {
+                if state.keys.remove(&name).is_some() {
+                    println!("removed key {}", name);
+                }
+            }
+            FolderTransfer::ListKeys => {
+                let mut names: Vec<&String> = state.keys.keys().collect();
+                names.sort();
+                println!("keys: {:?}", names);
+            }
+            // decrypts content of "send_to" directory, and puts it into "decrypted"
+            FolderTransfer::DecryptFolder {
+                folder,
+                destination,
+                key,
+                overwrite,
+            } => {
+                println!("DecryptFolder: {} -> {}", folder, destination);
+                let source_path = received_path(&send_to_path, &folder)?;
+                let (password, _) = transfer_password(state, &source_path, key)?;
+
+                // a subfolder of decrypt_to, so decrypting another transfer doesn't clobber this one
+                let destination_path = safe_join(&decrypt_to_path, &destination)?;
+                let occupied = open_dir(&destination_path, false, Some(5))
+                    .and_then(|dir| dir.read())
+                    .map(|entries| !entries.is_empty())
+                    .unwrap_or(false);
+                if occupied {
+                    if !overwrite {
+                        return Err(anyhow::anyhow!(
+                            "{} already has data, set overwrite to replace it",
+                            destination_path
+                        ));
+                    }
+                    // remove and re-create the destination so it's empty
+                    let request: VfsRequest = VfsRequest {
+                        path: destination_path.clone(),
+                        action: VfsAction::RemoveDirAll,
+                    };
+                    let _message = Request::new()
+                        .target(("our", "vfs", "distro", "sys"))
+                        .body(serde_json::to_vec(&request)?)
+                        .send_and_await_response(5)?;
+                }
                 let request: VfsRequest = VfsRequest {
-                    path: decrypt_to_path.clone(),
+                    path: destination_path.clone(),
                     action: VfsAction::CreateDirAll,
                 };
                 let _message = Request::new()
@@ -162,123 +1023,1056 @@ fn handle_message(
                     .body(serde_json::to_vec(&request)?)
                     .send_and_await_response(5)?;
 
-                // get all the paths, not content
-                let dir = read_nested_dir_light(dir_entry)?;
+                // session keys by salt, so each is derived only once
+                let mut sessions: HashMap<[u8; SALT_SIZE], SessionKey> = HashMap::new();
                 // iterate over all files, and decrypt each one
-                for path in dir.keys() {
-                    let mut active_file = open_file(path, false, Some(5))?;
-                    let size = active_file.metadata()?.len;
-                    // make sure we start from 0th position every time,
-                    // there were some bugs related to files not being closed, so we would start reading from the previous location
-                    let _pos = active_file.seek(SeekFrom::Start(0))?;
-
-                    // the path of each encrypted file looks like so:
-                    // folder_transfer:astronaut.os/send_to/GAXPVM7g...htLlOiu_E3A
-                    let path = Path::new(path);
-                    let file_name = path
-                        .file_name()
-                        .unwrap_or_default()
-                        .to_str()
-                        .unwrap_or_default()
-                        .to_string();
-                    // file name decryption
-                    //
-                    // base64/url_safe encoded encrypted file name -> base64 decoded (still encrypted)
-                    // base64 was necessary because of file names not accepting all encrypted chars
-                    let decoded_vec = general_purpose::URL_SAFE.decode(&file_name)?;
-                    // decoded, encrypted file name -> decrypted file name
-                    let decrypted_vec = match decrypt_data(&decoded_vec, "some_password") {
-                        Ok(vec) => vec,
-                        Err(e) => {
-                            println!("couldn't decrypt file name");
-                            return Err(anyhow::anyhow!(e));
-                        }
-                    };
-                    let decrypted_path = String::from_utf8(decrypted_vec)
-                        .map_err(|e| anyhow::anyhow!("Failed to convert bytes to string: {}", e))?;
-                    // get full file_path
-                    // one encrypted file name (e.g. q23ewdfvwerv) could be decrypted to a file nested in a folder (e.g. a/b/c/file.md)
-                    let file_path = format!("{}{}", decrypt_to_path.to_string(), decrypted_path);
-                    // parent path becomes e.g. a/b/c, separated out from a/b/c/file.md
-                    let parent_path = Path::new(&file_path)
-                        .parent()
-                        .and_then(|p| p.to_str())
-                        .unwrap_or("")
-                        .to_string();
-                    // creates nested parent directory (/a/b/c) all the way to the file
-                    let request = VfsRequest {
-                        path: parent_path.clone(),
-                        action: VfsAction::CreateDirAll,
-                    };
-                    let _message = Request::new()
-                        .target(("our", "vfs", "distro", "sys"))
-                        .body(serde_json::to_vec(&request)?)
-                        .send_and_await_response(5)?;
+                for path in encrypted_files(&source_path, legacy_folder(&folder))? {
+                    decrypt_file(&path, &password, &mut sessions, &destination_path)?;
+                }
+            }
+            // restores single files or subtrees, leaving the rest of send_to encrypted
+            FolderTransfer::DecryptPaths {
+                folder,
+                paths,
+                destination,
+                key,
+                overwrite,
+            } => {
+                println!("DecryptPaths: {:?} -> {}", paths, destination);
+                let source_path = received_path(&send_to_path, &folder)?;
+                let (password, kdf) = transfer_password(state, &source_path, key)?;
+                let mut sessions: HashMap<[u8; SALT_SIZE], SessionKey> = HashMap::new();
+                let index = load_index(&source_path, legacy_folder(&folder), &password, kdf, &mut sessions)?;
+                let selected = select_paths(&index, &paths)?;
 
-                    let _dir = open_dir(&parent_path[1..].to_string(), false, Some(5))?;
-
-                    // chunking and decrypting each file
-                    //
-                    // must be decrypted at specific encrypted chunk size.
-                    // encrypted chunk size = chunk size + 44, see files_lib/src/encryption.rs
-                    //
-                    // potential pitfall in the future is if we modify chunk size,
-                    // and try to decrypt at size non corresponding to the size at which it was encrypted.
-                    let num_chunks = (size as f64 / ENCRYPTED_CHUNK_SIZE as f64).ceil() as u64;
-
-                    // iterate over encrypted file
-                    for i in 0..num_chunks {
-                        let offset = i * ENCRYPTED_CHUNK_SIZE;
-                        let length = ENCRYPTED_CHUNK_SIZE.min(size - offset); // size=file size
-                        let mut buffer = vec![0; length as usize];
-                        let _pos = active_file.seek(SeekFrom::Current(0))?;
-                        active_file.read_at(&mut buffer)?;
-
-                        // decrypt data with password_hash
-                        let decrypted_bytes = match decrypt_data(&buffer, "some_password") {
-                            Ok(vec) => vec,
-                            Err(_e) => {
-                                println!("couldn't decrypt file data");
-                                return Err(anyhow::anyhow!("couldn't decrypt file data"));
-                            }
-                        };
-
-                        let dir = open_dir(&parent_path, false, None)?;
-
-                        // there is an issue with open_file(create: true), so we have to do it manually
-                        let entries = dir.read()?;
-                        if entries.contains(&DirEntry {
-                            path: file_path[1..].to_string(),
-                            file_type: FileType::File,
-                        }) {
-                        } else {
-                            let _file = create_file(&file_path, Some(5))?;
+                // checked before anything is written, so a refusal leaves the destination as it was
+                let destination_path = safe_join(&decrypt_to_path, &destination)?;
+                if !overwrite {
+                    for (original, _) in selected.iter() {
+                        let target = safe_join(&destination_path, original)?;
+                        if open_file(&target, false, Some(5)).is_ok() {
+                            return Err(anyhow::anyhow!(
+                                "{} already exists, set overwrite to replace it",
+                                target
+                            ));
                         }
+                    }
+                }
+                for (_, file_name) in selected {
+                    let path = format!("{}{}", &source_path[1..], file_name);
+                    decrypt_file(&path, &password, &mut sessions, &destination_path)?;
+                }
+            }
+        }
+    }
+
+    // one of our workers finishing up
+    let finished = state
+        .transfers
+        .iter()
+        .find(|(_, transfer)| &transfer.worker_address == message.source())
+        .map(|(transfer_id, _)| transfer_id.clone());
+    if let Some(transfer_id) = finished {
+        match serde_json::from_slice(message.body())? {
+            WorkerStatus::Progress(progress) => {
+                if let Some(transfer) = state.transfers.get_mut(&transfer_id) {
+                    transfer.progress = Some(progress);
+                    transfer.last_activity = now();
+                }
+                return Ok(());
+            }
+            // keeps the watchdog off, the progress we have is still the latest
+            WorkerStatus::Heartbeat => {
+                if let Some(transfer) = state.transfers.get_mut(&transfer_id) {
+                    transfer.last_activity = now();
+                }
+                return Ok(());
+            }
+            status => return finish(our, state, &transfer_id, status, EndedBy::Worker),
+        }
+    }
+
+    Ok(())
+}
+
+// ends requested and running transfers that went quiet for STALL_TIMEOUT seconds,
+// their worker may be stuck or the other node gone,
+// and withdraws requests and offers the other node hasn't approved within APPROVAL_TIMEOUT
+// paused transfers are quiet on purpose
+fn watchdog(our: &Address, state: &mut State) -> anyhow::Result<()> {
+    let now = now();
+    let stalled: Vec<(String, u64, bool)> = state
+        .transfers
+        .iter()
+        .filter_map(|(transfer_id, transfer)| {
+            let secs = now.saturating_sub(transfer.last_activity);
+            let timed_out = match transfer.status {
+                TransferStatus::Requested | TransferStatus::Running => secs >= STALL_TIMEOUT,
+                TransferStatus::AwaitingApproval => secs >= APPROVAL_TIMEOUT,
+                TransferStatus::Paused => false,
+            };
+            timed_out.then(|| {
+                let awaiting = transfer.status == TransferStatus::AwaitingApproval;
+                (transfer_id.clone(), secs, awaiting)
+            })
+        })
+        .collect();
+    for (transfer_id, secs, awaiting) in stalled {
+        // the other node holds it as a pending request, cancelling it takes it off that list
+        let ended_by = if awaiting {
+            EndedBy::Us { discard: false }
+        } else {
+            EndedBy::Watchdog
+        };
+        finish(our, state, &transfer_id, WorkerStatus::TimedOut { secs }, ended_by)?;
+    }
+
+    let expired: Vec<(String, Offer)> = state
+        .offers
+        .iter()
+        .filter(|(_, offer)| now.saturating_sub(offer.created) >= APPROVAL_TIMEOUT)
+        .map(|(transfer_id, offer)| (transfer_id.clone(), offer.clone()))
+        .collect();
+    for (transfer_id, offer) in expired {
+        state.offers.remove(&transfer_id);
+        println!(
+            "transfer {}: {} didn't answer the offer of {} in time",
+            transfer_id, offer.peer_node, offer.folder
+        );
+        // the other node holds it as a pending offer, this takes it off that list
+        let control = FolderTransfer::TransferControl {
+            transfer_id,
+            action: TransferAction::Cancel { discard: false },
+        };
+        reply_to(our, &offer.peer_node, &control)?;
+    }
+    Ok(())
+}
+
+// seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// pauses or resumes a running transfer, passing it on to the other node when we are the one asking
+fn control_transfer(
+    our: &Address,
+    state: &mut State,
+    transfer_id: &str,
+    action: TransferAction,
+    tell_peer: bool,
+) -> anyhow::Result<()> {
+    let Some(transfer) = state.transfers.get_mut(transfer_id) else {
+        return Err(anyhow::anyhow!("no transfer {}", transfer_id));
+    };
+    let (from, to, request) = match action {
+        TransferAction::Pause => (TransferStatus::Running, TransferStatus::Paused, WorkerRequest::Pause),
+        TransferAction::Resume => (TransferStatus::Paused, TransferStatus::Running, WorkerRequest::Resume),
+        TransferAction::Cancel { .. } => return Err(anyhow::anyhow!("cancelling goes through finish")),
+    };
+    if transfer.status != from {
+        return Err(anyhow::anyhow!("transfer {} is {:?}", transfer_id, transfer.status));
+    }
+    println!("transfer {}: {:?}", transfer_id, to);
+    transfer.status = to;
+    transfer.last_activity = now();
+    Request::to(&transfer.worker_address)
+        .body(serde_json::to_vec(&request)?)
+        .send()?;
+    if tell_peer {
+        let peer_node = transfer.peer_node.clone();
+        reply_to(
+            our,
+            &peer_node,
+            &FolderTransfer::TransferControl {
+                transfer_id: transfer_id.to_string(),
+                action,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+// ends transfer `transfer_id` with `status`, keeping it in the history
+fn finish(
+    our: &Address,
+    state: &mut State,
+    transfer_id: &str,
+    status: WorkerStatus,
+    ended_by: EndedBy,
+) -> anyhow::Result<()> {
+    let Some(transfer) = state.transfers.remove(transfer_id) else {
+        return Ok(());
+    };
+    match &status {
+        WorkerStatus::Done => println!("transfer {}: done", transfer_id),
+        WorkerStatus::PartiallyDone { files_done, failed } => println!(
+            "transfer {}: {} files done, {} did not verify: {:?}",
+            transfer_id,
+            files_done,
+            failed.len(),
+            failed
+        ),
+        WorkerStatus::Rejected { reason } => println!("transfer {}: rejected: {}", transfer_id, reason),
+        WorkerStatus::Failed { reason, file } => match file {
+            Some(file) => println!("transfer {}: failed on {}: {}", transfer_id, file, reason),
+            None => println!("transfer {}: failed: {}", transfer_id, reason),
+        },
+        WorkerStatus::TimedOut { secs } => {
+            println!("transfer {}: timed out, nothing for {} seconds", transfer_id, secs)
+        }
+        WorkerStatus::Cancelled { by } => println!("transfer {}: cancelled by {}", transfer_id, by),
+        WorkerStatus::Progress(_) | WorkerStatus::Heartbeat => {}
+    }
+
+    let reason = match &status {
+        WorkerStatus::Failed { reason, .. } | WorkerStatus::Rejected { reason } => reason.clone(),
+        WorkerStatus::Cancelled { by } => format!("cancelled by {}", by),
+        WorkerStatus::TimedOut { secs } => format!("no sign of life for {} seconds", secs),
+        _ => "transfer ended".to_string(),
+    };
+    let (tell_peer, abort) = match ended_by {
+        EndedBy::Worker => (
+            Some(FolderTransfer::TransferOutcome {
+                transfer_id: transfer_id.to_string(),
+                status: status.clone(),
+            }),
+            None,
+        ),
+        EndedBy::Peer { discard } => (None, Some(WorkerRequest::Abort { reason, discard })),
+        EndedBy::Us { discard } => (
+            Some(FolderTransfer::TransferControl {
+                transfer_id: transfer_id.to_string(),
+                action: TransferAction::Cancel { discard },
+            }),
+            Some(WorkerRequest::Abort { reason, discard }),
+        ),
+        EndedBy::Watchdog => (
+            Some(FolderTransfer::TransferOutcome {
+                transfer_id: transfer_id.to_string(),
+                status: status.clone(),
+            }),
+            Some(WorkerRequest::Abort { reason, discard: false }),
+        ),
+    };
+    if let Some(abort) = abort {
+        Request::to(&transfer.worker_address)
+            .body(serde_json::to_vec(&abort)?)
+            .send()?;
+    }
+    if let Some(message) = tell_peer {
+        reply_to(our, &transfer.peer_node, &message)?;
+    }
+
+    state.history.push_back(Outcome {
+        transfer_id: transfer_id.to_string(),
+        role: transfer.role,
+        peer_node: transfer.peer_node,
+        folder: transfer.folder,
+        status,
+        peer_status: transfer.peer_status,
+    });
+    while state.history.len() > HISTORY_SIZE {
+        state.history.pop_front();
+    }
+    Ok(())
+}
+
+// spawns our receiving worker for the folder `spec` names, and records the transfer
+// returns the worker's address, when encrypting to our public key that key,
+// and the token the sending worker has to present, all for the sender
+fn start_receiving(
+    our: &Address,
+    state: &mut State,
+    send_to_path: &str,
+    transfer_id: &str,
+    spec: TransferSpec,
+) -> anyhow::Result<(Address, Option<[u8; PUBLIC_KEY_SIZE]>, String)> {
+    let TransferSpec {
+        peer_node: node_id,
+        folder,
+        encrypt,
+        key,
+        decrypt_on_receive,
+        on_conflict,
+        ..
+    } = spec;
+    // the id is chosen by the node that starts the transfer, so it may not clash with one we track
+    if state.transfers.contains_key(transfer_id) {
+        return Err(anyhow::anyhow!("transfer {} already exists", transfer_id));
+    }
+
+    // we need the same key to decrypt what we receive
+    if let Some(key) = &key {
+        if encrypt && !state.keys.contains_key(key) {
+            return Err(anyhow::anyhow!("no key named {}", key));
+        }
+    }
+    let key = if encrypt { key } else { None };
+    // no shared key, the sender encrypts to our public key instead
+    let public_key = match (&key, &state.keypair) {
+        (None, Some((_, public_key))) if encrypt => Some(*public_key),
+        _ => None,
+    };
+    // our receiving worker gets what it needs to decrypt with, it never leaves our node
+    let decrypt = match (&key, &state.keypair) {
+        (_, _) if !(encrypt && decrypt_on_receive) => None,
+        (Some(key), _) => state.keys.get(key).cloned().map(DecryptWith::Password),
+        (None, Some((secret, _))) => Some(DecryptWith::KeyExchange { secret: *secret }),
+        (None, None) => None,
+    };
+
+    // every transfer lands in its own folder, send_to/<node_id>/<folder>
+    let receive_dir = prepare_receive_dir(
+        state,
+        send_to_path,
+        &node_id,
+        &folder,
+        on_conflict.unwrap_or(state.conflict_policy),
+        encrypt && decrypt.is_none(),
+    )?;
+
+    // spin up worker process
+    let worker_address = initialize_worker(our)?;
+    let token = new_token();
+
+    println!("receive_dir: {}", &receive_dir[1..]);
+    // start receiving data on the worker
+    Request::new()
+        .body(serde_json::to_vec(
+            &WorkerRequest::InitializeReceiverWorker {
+                receive_to_dir: receive_dir[1..].to_string(),
+                peer_node: node_id.to_string(),
+                token: token.clone(),
+                max_bytes: state.max_transfer_bytes,
+                decrypt,
+            },
+        )?)
+        .target(&worker_address)
+        .send()?;
+
+    println!("transfer {}: receiving {} from {}", transfer_id, folder, node_id);
+    state.transfers.insert(
+        transfer_id.to_string(),
+        Transfer {
+            role: TransferRole::Receiver,
+            worker_address: worker_address.clone(),
+            peer_node: node_id,
+            folder,
+            encrypt,
+            key,
+            receive_dir: Some(receive_dir.clone()),
+            status: TransferStatus::Requested,
+            summary: None,
+            progress: None,
+            last_activity: now(),
+            peer_status: None,
+        },
+    );
+    Ok((worker_address, public_key, token))
+}
+
+// spawns our sending worker for the folder `spec` names, streaming to `target_worker`,
+// which has to run on the peer node, and records the transfer
+fn start_sending(
+    our: &Address,
+    state: &mut State,
+    send_from_path: &str,
+    transfer_id: String,
+    spec: TransferSpec,
+    target_worker: Address,
+    token: String,
+) -> anyhow::Result<()> {
+    let TransferSpec {
+        peer_node: node_id,
+        folder,
+        encrypt,
+        key,
+        public_key,
+        window,
+        ..
+    } = spec;
+    // the id is chosen by the node that starts the transfer, so it may not clash with one we track
+    if state.transfers.contains_key(&transfer_id) {
+        return Err(anyhow::anyhow!("transfer {} already exists", transfer_id));
+    }
+    // the receiving worker runs on the node that asked, we don't send its folder anywhere else
+    if target_worker.node() != node_id {
+        return Err(anyhow::anyhow!(
+            "{} named a worker on {}",
+            node_id,
+            target_worker.node()
+        ));
+    }
+
+    // either the receiver names a key we hold a password under,
+    // or we derive one from its public key and a fresh ephemeral keypair
+    let (password, ephemeral_public_key) = match (encrypt, &key, public_key) {
+        (false, _, _) => (None, None),
+        (true, Some(key), _) => {
+            let Some(password) = state.keys.get(key) else {
+                return Err(anyhow::anyhow!("no key named {} to encrypt with", key));
+            };
+            (Some(password.clone()), None)
+        }
+        (true, None, Some(public_key)) => {
+            let (ephemeral_secret, ephemeral_public_key) = generate_keypair();
+            (
+                Some(shared_password(&ephemeral_secret, &public_key)),
+                Some(ephemeral_public_key),
+            )
+        }
+        (true, None, None) => {
+            return Err(anyhow::anyhow!("nothing to encrypt with, no key nor public key"));
+        }
+    };
+
+    // spin up worker process
+    let our_worker_address = initialize_worker(our)?;
+
+    // the folder name comes from the other node
+    let sending_dir = safe_join(send_from_path, &folder)?;
+    println!("send_from_path: {}", &sending_dir[1..]);
+    // start sending data from worker
+    Request::new()
+        .body(serde_json::to_vec(
+            &WorkerRequest::InitializeSenderWorker {
+                target_worker: Some(target_worker),
+                sending_dir: sending_dir[1..].to_string(),
+                password,
+                ephemeral_public_key,
+                window: window.unwrap_or(DEFAULT_WINDOW),
+                token,
+            },
+        )?)
+        .target(&our_worker_address)
+        .send()?;
+
+    println!("transfer {}: sending {} to {}", transfer_id, folder, node_id);
+    state.transfers.insert(
+        transfer_id,
+        Transfer {
+            role: TransferRole::Sender,
+            worker_address: our_worker_address,
+            peer_node: node_id,
+            folder,
+            encrypt,
+            key,
+            receive_dir: None,
+            status: TransferStatus::Running,
+            summary: None,
+            progress: None,
+            last_activity: now(),
+            peer_status: None,
+        },
+    );
+    Ok(())
+}
+
+// whether `node` may pull `folder`, a rule for the node itself wins over one for "*"
+fn check_access(state: &State, folder: &str, node: &str) -> Result<(), String> {
+    let access = state
+        .folder_access
+        .get(folder.trim_matches('/'))
+        .and_then(|nodes| nodes.get(node).or_else(|| nodes.get("*")));
+    match access {
+        Some(Access::Read) => Ok(()),
+        Some(Access::Denied) => Err(format!("{} is denied access to {}", node, folder)),
+        None => Err(format!("{} has no access to {}", node, folder)),
+    }
+}
+
+// a node picks the transfer id, so it mustn't clash with one we already know about
+fn check_unseen(state: &State, transfer_id: &str) -> Result<(), String> {
+    if state.pending.contains_key(transfer_id) || state.transfers.contains_key(transfer_id) {
+        return Err(format!("transfer {} already exists", transfer_id));
+    }
+    Ok(())
+}
+
+// a rule for the node itself wins over one for "*"
+fn auto_approve(state: &State, node: &str) -> AutoApprove {
+    state
+        .auto_approve
+        .get(node)
+        .or_else(|| state.auto_approve.get("*"))
+        .copied()
+        .unwrap_or_default()
+}
 
-                        let mut file = open_file(&file_path, false, Some(5))?;
-                        file.append(&decrypted_bytes)?;
+fn hold(state: &mut State, transfer_id: String, pending: PendingRequest) {
+    match &pending {
+        PendingRequest::Pull { peer_node, folder, .. } => println!(
+            "transfer {}: {} asks for {}, ApproveRequest or DenyRequest it",
+            transfer_id, peer_node, folder
+        ),
+        PendingRequest::Push { peer_node, folder, .. } => println!(
+            "transfer {}: {} offers {}, ApproveRequest or DenyRequest it",
+            transfer_id, peer_node, folder
+        ),
+    }
+    state.pending.insert(transfer_id, pending);
+}
+
+// goes ahead with a request or offer, sending the folder or spawning a worker to receive it
+// returns what to answer the node with
+fn approve(
+    our: &Address,
+    state: &mut State,
+    send_from_path: &str,
+    send_to_path: &str,
+    transfer_id: String,
+    pending: PendingRequest,
+) -> anyhow::Result<FolderTransfer> {
+    match pending {
+        PendingRequest::Pull {
+            peer_node,
+            worker_address,
+            folder,
+            encrypt,
+            key,
+            public_key,
+            window,
+            token,
+        } => {
+            // checked again, access may have been taken away while the request waited
+            let result = match check_pull(state, send_from_path, &transfer_id, &folder, &peer_node) {
+                Err(result) => result,
+                Ok(summary) => {
+                    let spec = TransferSpec {
+                        peer_node,
+                        folder,
+                        encrypt,
+                        key,
+                        public_key,
+                        window,
+                        ..Default::default()
+                    };
+                    let sending = start_sending(
+                        our,
+                        state,
+                        send_from_path,
+                        transfer_id.clone(),
+                        spec,
+                        worker_address,
+                        token,
+                    );
+                    match sending {
+                        Ok(()) => RequestFolderResult::Accepted { summary },
+                        Err(e) => RequestFolderResult::Failed { reason: e.to_string() },
                     }
                 }
+            };
+            if !matches!(result, RequestFolderResult::Accepted { .. }) {
+                println!("transfer {}: refused: {:?}", transfer_id, result);
             }
+            Ok(FolderTransfer::RequestFolderResponse {
+                transfer_id,
+                result,
+            })
+        }
+        PendingRequest::Push {
+            peer_node,
+            folder,
+            encrypt,
+            key,
+        } => {
+            let spec = TransferSpec {
+                peer_node,
+                folder,
+                encrypt,
+                key,
+                ..Default::default()
+            };
+            let received = start_receiving(our, state, send_to_path, &transfer_id, spec);
+            Ok(match received {
+                Ok((worker_address, public_key, token)) => {
+                    if let Some(transfer) = state.transfers.get_mut(&transfer_id) {
+                        transfer.status = TransferStatus::Running;
+                        transfer.last_activity = now();
+                    }
+                    FolderTransfer::SendFolderAccepted {
+                        transfer_id,
+                        worker_address,
+                        public_key,
+                        token,
+                    }
+                }
+                Err(e) => {
+                    println!("transfer {}: declined: {}", transfer_id, e);
+                    FolderTransfer::SendFolderDeclined {
+                        transfer_id,
+                        reason: e.to_string(),
+                    }
+                }
+            })
         }
     }
+}
 
-    // current worker finishing up
-    if let Some(worker_address) = current_worker_address {
-        if worker_address == message.source() {
-            match serde_json::from_slice(&message.body())? {
-                WorkerStatus::Done => {
-                    *current_worker_address = None;
-                    println!("received status: done");
-                    return Ok(());
+// what to tell the node when we won't go ahead with its request or offer
+fn denial(transfer_id: String, pending: PendingRequest, reason: String) -> FolderTransfer {
+    match pending {
+        PendingRequest::Pull { .. } => FolderTransfer::RequestFolderResponse {
+            transfer_id,
+            result: RequestFolderResult::AccessDenied { reason },
+        },
+        PendingRequest::Push { .. } => FolderTransfer::SendFolderDeclined { transfer_id, reason },
+    }
+}
+
+// sends `reply` to our process on `node`, outside of a response
+fn reply_to(our: &Address, node: &str, reply: &FolderTransfer) -> anyhow::Result<()> {
+    Request::to(Address::new(node, our.process.clone()))
+        .body(serde_json::to_vec(reply)?)
+        .send()
+}
+
+// whether `node` may pull `folder` as transfer `transfer_id`, and what the folder holds if so
+// access is checked before the folder is looked up, so nodes without it can't tell what exists
+fn check_pull(
+    state: &State,
+    send_from_path: &str,
+    transfer_id: &str,
+    folder: &str,
+    node: &str,
+) -> Result<FolderSummary, RequestFolderResult> {
+    let path = safe_join(send_from_path, folder)
+        .map_err(|e| RequestFolderResult::AccessDenied { reason: e.to_string() })?;
+    check_access(state, folder, node).map_err(|reason| RequestFolderResult::AccessDenied { reason })?;
+    let summary = folder_summary(&path).map_err(|_| RequestFolderResult::NotFound)?;
+    check_unseen(state, transfer_id).map_err(|reason| RequestFolderResult::Busy { reason })?;
+    let folder = folder.trim_matches('/');
+    let sending = state.transfers.values().any(|transfer| {
+        transfer.role == TransferRole::Sender
+            && transfer.peer_node == node
+            && transfer.folder.trim_matches('/') == folder
+    });
+    if sending {
+        return Err(RequestFolderResult::Busy {
+            reason: format!("already sending {} to {}", folder, node),
+        });
+    }
+    Ok(summary)
+}
+
+// how many files `path` holds, and how big they are together
+// that's a metadata call per file before answering, the requester waits up to 30 seconds for it
+fn folder_summary(path: &str) -> anyhow::Result<FolderSummary> {
+    let files = read_nested_dir_light(DirEntry {
+        path: path.to_string(),
+        file_type: FileType::Directory,
+    })?;
+    let mut bytes = 0;
+    for file_path in files.keys() {
+        bytes += open_file(file_path, false, Some(5))?.metadata()?.len;
+    }
+    Ok(FolderSummary {
+        files: files.len() as u64,
+        bytes,
+    })
+}
+
+// our offer `transfer_id`, if it was made to `node`
+fn take_offer(state: &mut State, transfer_id: &str, node: &str) -> Option<Offer> {
+    if state.offers.get(transfer_id)?.peer_node != node {
+        return None;
+    }
+    state.offers.remove(transfer_id)
+}
+
+// a received folder to decrypt, an empty one is send_to itself,
+// where everything landed before transfers got a folder of their own
+fn received_path(send_to_path: &str, folder: &str) -> anyhow::Result<String> {
+    if legacy_folder(folder) {
+        return Ok(send_to_path.to_string());
+    }
+    safe_join(send_to_path, folder)
+}
+
+// what is in a directory, nothing if it doesn't exist
+fn dir_entries(path: &str) -> Vec<DirEntry> {
+    open_dir(path, false, Some(5))
+        .and_then(|dir| dir.read())
+        .unwrap_or_default()
+}
+
+// picks the directory an incoming folder is stored in, clearing it out if the policy says so
+// a directory a live transfer is receiving into is never picked, whatever the policy
+fn prepare_receive_dir(
+    state: &State,
+    send_to_path: &str,
+    node_id: &str,
+    folder: &str,
+    policy: ConflictPolicy,
+    encrypted_at_rest: bool,
+) -> anyhow::Result<String> {
+    let folder_name = receive_dir_name(folder)?;
+    let base = safe_join(&safe_join(send_to_path, node_id)?, &folder_name)?;
+    let in_use = |path: &str| {
+        state
+            .transfers
+            .values()
+            .any(|transfer| transfer.receive_dir.as_deref() == Some(path))
+    };
+    // empty, or a transfer into it was interrupted and will be resumed
+    let usable = |path: &str| {
+        if in_use(path) {
+            return false;
+        }
+        let entries = dir_entries(path);
+        entries.is_empty()
+            || entries
+                .iter()
+                .any(|entry| entry.path.ends_with(CHECKPOINT_FILE))
+    };
+    if usable(&base) {
+        return Ok(base);
+    }
+    match conflict_policy(policy, in_use(&base), encrypted_at_rest) {
+        ConflictPolicy::Overwrite => {
+            let request: VfsRequest = VfsRequest {
+                path: base.clone(),
+                action: VfsAction::RemoveDirAll,
+            };
+            let _message = Request::new()
+                .target(("our", "vfs", "distro", "sys"))
+                .body(serde_json::to_vec(&request)?)
+                .send_and_await_response(5)?;
+            Ok(base)
+        }
+        ConflictPolicy::Rename => {
+            let mut n = 1;
+            loop {
+                let renamed = format!("{} ({})", base, n);
+                if usable(&renamed) {
+                    return Ok(renamed);
                 }
+                n += 1;
             }
         }
+        ConflictPolicy::Merge => Ok(base),
+        ConflictPolicy::Reject => Err(anyhow::anyhow!(
+            "{} already holds {} from {}",
+            base,
+            folder,
+            node_id
+        )),
+    }
+}
+
+// what `policy` comes down to for a folder that already holds data
+// one still being received would clash with that transfer if cleared out or merged into,
+// and encrypted names differ on every transfer, so merging ciphertext would keep both copies of every file
+// (and the key of an earlier key exchange would be replaced)
+fn conflict_policy(policy: ConflictPolicy, in_use: bool, encrypted_at_rest: bool) -> ConflictPolicy {
+    match policy {
+        ConflictPolicy::Reject => ConflictPolicy::Reject,
+        _ if in_use => ConflictPolicy::Rename,
+        ConflictPolicy::Merge if encrypted_at_rest => ConflictPolicy::Rename,
+        policy => policy,
+    }
+}
+
+// one directory name for the whole folder path, so "a/docs" and "b/docs" don't share one
+// "docs" stays "docs", "a/docs" becomes "a%2Fdocs"
+fn receive_dir_name(folder: &str) -> anyhow::Result<String> {
+    let components: Vec<String> = safe_join("", folder)?
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .map(|component| component.replace('%', "%25"))
+        .collect();
+    Ok(components.join("%2F"))
+}
+
+// the password a folder in send_to was encrypted with, and the kdf a key like it is used with
+fn transfer_password(
+    state: &State,
+    send_to_path: &str,
+    key: Option<String>,
+) -> anyhow::Result<(String, Kdf)> {
+    match key {
+        Some(key) => match state.keys.get(&key) {
+            Some(password) => Ok((password.clone(), Kdf::SCRYPT_DEFAULT)),
+            None => Err(anyhow::anyhow!("no key named {}", key)),
+        },
+        // encrypted to our public key, the sender's half of the exchange is stored with the data
+        None => {
+            let Some((secret, _)) = &state.keypair else {
+                return Err(anyhow::anyhow!("no keypair to decrypt with"));
+            };
+            let key_file =
+                open_file(&format!("{}/{}", send_to_path, TRANSFER_KEY_FILE), false, Some(5))?;
+            let ephemeral_public_key: [u8; PUBLIC_KEY_SIZE] =
+                serde_json::from_slice(&key_file.read()?)?;
+            Ok((shared_password(secret, &ephemeral_public_key), Kdf::Hkdf))
+        }
+    }
+}
+
+// every encrypted file in send_to, without our bookkeeping files
+// send_to/<node>/<folder>, or send_to itself for the files of older versions,
+// this is the dir where we transfered the folder in an encrypted form
+// the files of older versions sit right in send_to, next to the <node> folders of newer transfers,
+// which may be plaintext or under other keys, so only the top level is listed for them
+fn encrypted_files(send_to_path: &str, legacy: bool) -> anyhow::Result<Vec<String>> {
+    let dir_entry: DirEntry = DirEntry {
+        path: send_to_path.to_string(),
+        file_type: FileType::Directory,
+    };
+    // get all the paths, not content
+    let entries = if legacy {
+        read_dir(dir_entry)?
+    } else {
+        read_nested_dir_light(dir_entry)?
+            .into_keys()
+            .map(|path| DirEntry {
+                path,
+                file_type: FileType::File,
+            })
+            .collect()
+    };
+    Ok(encrypted_entries(entries))
+}
+
+// the files among `entries` holding encrypted data, leaving out folders and our bookkeeping
+fn encrypted_entries(entries: Vec<DirEntry>) -> Vec<String> {
+    entries
+        .into_iter()
+        .filter(|entry| entry.file_type == FileType::File)
+        .map(|entry| entry.path)
+        .filter(|path| {
+            !path.ends_with(CHECKPOINT_FILE)
+                && !path.ends_with(TRANSFER_KEY_FILE)
+                && !path.ends_with(INDEX_FILE)
+        })
+        .collect()
+}
+
+// the (original path, encrypted file name) pairs of the index that `paths` name,
+// each one a file or a folder of the received folder, "" or "/" being all of it
+fn select_paths(
+    index: &HashMap<String, String>,
+    paths: &[String],
+) -> anyhow::Result<Vec<(String, String)>> {
+    let mut selected: Vec<(String, String)> = Vec::new();
+    for requested in paths.iter() {
+        // the index holds paths within the received folder, like /notes/todo.md
+        let requested = format!("/{}", requested.trim_matches('/'));
+        let matching: Vec<(String, String)> = index
+            .iter()
+            .filter(|(original, _)| {
+                requested == "/"
+                    || **original == requested
+                    || original.starts_with(&format!("{}/", requested))
+            })
+            .map(|(original, encrypted)| (original.clone(), encrypted.clone()))
+            .collect();
+        if matching.is_empty() {
+            return Err(anyhow::anyhow!("{} is not in the received folder", requested));
+        }
+        selected.extend(matching);
     }
+    selected.sort();
+    selected.dedup();
+    Ok(selected)
+}
+
+// an empty folder names send_to itself, see received_path
+fn legacy_folder(folder: &str) -> bool {
+    folder.trim_matches('/').is_empty()
+}
+
+// file name decryption
+//
+// base64/url_safe encoded encrypted file name -> base64 decoded (still encrypted)
+// base64 was necessary because of file names not accepting all encrypted chars
+//
+// returns the original path, and the header for decrypting the content when in the session format
+fn decrypt_file_name(
+    file_name: &str,
+    password: &str,
+    sessions: &mut HashMap<[u8; SALT_SIZE], SessionKey>,
+) -> anyhow::Result<(String, Option<FileHeader>)> {
+    let decoded_vec = general_purpose::URL_SAFE.decode(file_name)?;
+    // decoded, encrypted file name -> decrypted file name
+    //
+    // names encrypted in a session start with a header naming how the session key was derived
+    // and the file's index, anything else was encrypted with encrypt_data
+    let header = FileHeader::parse(&decoded_vec).ok().map(|(header, _)| header);
+    let decrypted_name = match &header {
+        Some(header) => sessions
+            .entry(header.salt)
+            .or_insert_with(|| SessionKey::derive(password, header.kdf, &header.salt))
+            .decrypt_name(header.file_index, &decoded_vec[header.size()..])
+            .map(String::into_bytes),
+        None => decrypt_data(&decoded_vec, password),
+    };
+    let decrypted_vec = match decrypted_name {
+        Ok(vec) => vec,
+        Err(e) => {
+            println!("couldn't decrypt file name");
+            return Err(anyhow::anyhow!(e));
+        }
+    };
+    let decrypted_path = String::from_utf8(decrypted_vec)
+        .map_err(|e| anyhow::anyhow!("Failed to convert bytes to string: {}", e))?;
+    Ok((decrypted_path, header))
+}
 
+// decrypts one file of send_to into decrypt_to, under its original path
+fn decrypt_file(
+    path: &str,
+    password: &str,
+    sessions: &mut HashMap<[u8; SALT_SIZE], SessionKey>,
+    decrypt_to_path: &str,
+) -> anyhow::Result<()> {
+    let mut active_file = open_file(path, false, Some(5))?;
+    let size = active_file.metadata()?.len;
+
+    // the path of each encrypted file looks like so:
+    // folder_transfer:astronaut.os/send_to/GAXPVM7g...htLlOiu_E3A
+    let file_name = Path::new(path)
+        .file_name()
+        .unwrap_or_default()
+        .to_str()
+        .unwrap_or_default()
+        .to_string();
+    let (decrypted_path, header) = decrypt_file_name(&file_name, password, sessions)?;
+    // get full file_path
+    // one encrypted file name (e.g. q23ewdfvwerv) could be decrypted to a file nested in a folder (e.g. a/b/c/file.md)
+    // the name was chosen by the sending node
+    let file_path = safe_join(decrypt_to_path, &decrypted_path)?;
+    // parent path becomes e.g. a/b/c, separated out from a/b/c/file.md
+    let parent_path = Path::new(&file_path)
+        .parent()
+        .and_then(|p| p.to_str())
+        .unwrap_or("")
+        .to_string();
+    // creates nested parent directory (/a/b/c) all the way to the file
+    let request = VfsRequest {
+        path: parent_path.clone(),
+        action: VfsAction::CreateDirAll,
+    };
+    let _message = Request::new()
+        .target(("our", "vfs", "distro", "sys"))
+        .body(serde_json::to_vec(&request)?)
+        .send_and_await_response(5)?;
+
+    let dir = open_dir(&parent_path, false, None)?;
+
+    // there is an issue with open_file(create: true), so we have to do it manually
+    let entries = dir.read()?;
+    if !entries.contains(&DirEntry {
+        path: file_path[1..].to_string(),
+        file_type: FileType::File,
+    }) {
+        let _file = create_file(&file_path, Some(5))?;
+    }
+    let mut file = open_file(&file_path, false, Some(5))?;
+    // restoring over an earlier copy
+    file.set_len(0)?;
+
+    // chunking and decrypting each file
+    //
+    // session encrypted files describe themselves: a header up front,
+    // then chunks that are prefixed with their length from FORMAT_VERSION on.
+    // older files must be decrypted at the chunk size they were encrypted at,
+    // encrypted chunk size = chunk size + 44, see files_lib/src/encryption.rs
+    let (mut offset, stored_chunk_size) = match &header {
+        Some(header) => (header.size() as u64, header.stored_chunk_size()),
+        None => (0, ENCRYPTED_CHUNK_SIZE),
+    };
+    let framed = header.as_ref().map(|header| header.framed()).unwrap_or(false);
+
+    // iterate over encrypted file
+    let mut i = 0;
+    while offset < size {
+        let length = if framed {
+            let mut frame = [0u8; FRAME_SIZE as usize];
+            let _pos = active_file.seek(SeekFrom::Start(offset))?;
+            active_file.read_at(&mut frame)?;
+            offset += FRAME_SIZE;
+            u32::from_be_bytes(frame) as u64
+        } else {
+            stored_chunk_size
+        };
+        let length = length.min(size.saturating_sub(offset)); // size=file size
+        let mut buffer = vec![0; length as usize];
+        let _pos = active_file.seek(SeekFrom::Start(offset))?;
+        active_file.read_at(&mut buffer)?;
+        offset += length;
+
+        // decrypt data with the session key, or password_hash for the older format
+        let decrypted = match &header {
+            Some(header) => sessions[&header.salt].decrypt_chunk(header.file_index, i, &buffer),
+            None => decrypt_data(&buffer, password),
+        };
+        let decrypted_bytes = match decrypted {
+            Ok(vec) => vec,
+            Err(_e) => {
+                println!("couldn't decrypt file data");
+                return Err(anyhow::anyhow!("couldn't decrypt file data"));
+            }
+        };
+
+        file.append(&decrypted_bytes)?;
+        i += 1;
+    }
     Ok(())
 }
 
+// original path -> encrypted file name, for everything in send_to
+// kept encrypted in send_to, and rebuilt when it no longer matches the files there
+fn load_index(
+    send_to_path: &str,
+    legacy: bool,
+    password: &str,
+    kdf: Kdf,
+    sessions: &mut HashMap<[u8; SALT_SIZE], SessionKey>,
+) -> anyhow::Result<HashMap<String, String>> {
+    let index_path = format!("{}/{}", send_to_path, INDEX_FILE);
+    let mut file_names: Vec<String> = encrypted_files(send_to_path, legacy)?
+        .iter()
+        .map(|path| path[send_to_path.len() - 1..].to_string())
+        .collect();
+    file_names.sort();
+
+    let stored: Option<HashMap<String, String>> = open_file(&index_path, false, Some(5))
+        .ok()
+        .and_then(|file| file.read().ok())
+        .and_then(|bytes| open_blob(password, &bytes).ok())
+        .and_then(|json| serde_json::from_slice(&json).ok());
+    if let Some(index) = stored {
+        let mut indexed: Vec<String> = index.values().cloned().collect();
+        indexed.sort();
+        if indexed == file_names {
+            return Ok(index);
+        }
+    }
+
+    println!("building index of {} files", file_names.len());
+    let mut index = HashMap::new();
+    for file_name in file_names {
+        let (original, _) =
+            decrypt_file_name(file_name.trim_start_matches('/'), password, sessions)?;
+        index.insert(original, file_name);
+    }
+    let dir = open_dir(send_to_path, false, None)?;
+    if !dir.read()?.contains(&DirEntry {
+        path: index_path[1..].to_string(),
+        file_type: FileType::File,
+    }) {
+        let _file = create_file(&index_path, Some(5))?;
+    }
+    let file = open_file(&index_path, false, Some(5))?;
+    file.write(&seal_blob(password, kdf, &serde_json::to_vec(&index)?))?;
+    Ok(index)
+}
+
 call_init!(init);
 fn init(our: Address) {
     println!("folder_transfer: begin");
@@ -286,12 +2080,20 @@ fn init(our: Address) {
     let send_from_path = create_drive(our.package_id(), "send_from", Some(5)).unwrap();
     let send_to_path = create_drive(our.package_id(), "send_to", Some(5)).unwrap();
     let decrypt_to_path = create_drive(our.package_id(), "decrypt_to", Some(5)).unwrap();
-    let mut current_worker_address: Option<Address> = None;
+    let mut state = load_state();
+    // transfers loaded from a previous run get a full STALL_TIMEOUT before the watchdog looks at them
+    for transfer in state.transfers.values_mut() {
+        transfer.last_activity = now();
+    }
+    timer::set_timer(WATCHDOG_INTERVAL * 1000, None);
+    if state.keypair.is_none() {
+        state.keypair = Some(generate_keypair());
+    }
 
     loop {
         match handle_message(
             &our,
-            &mut current_worker_address,
+            &mut state,
             send_from_path.clone(),
             send_to_path.clone(),
             decrypt_to_path.clone(),
@@ -299,5 +2101,93 @@ fn init(our: Address) {
             Ok(_) => {}
             Err(e) => println!("Error: {:?}", e),
         }
+        if let Err(e) = save_state(&state) {
+            println!("Error: couldn't save state: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receive_dir_name_keeps_the_whole_path() {
+        assert_eq!(receive_dir_name("docs").unwrap(), "docs");
+        assert_eq!(receive_dir_name("/docs/").unwrap(), "docs");
+        assert_eq!(receive_dir_name("a/docs").unwrap(), "a%2Fdocs");
+        assert_ne!(receive_dir_name("a/docs").unwrap(), receive_dir_name("b/docs").unwrap());
+        // an escaped slash in a name can't pass for a real one
+        assert_eq!(receive_dir_name("a%2Fdocs").unwrap(), "a%252Fdocs");
+        assert_ne!(receive_dir_name("a%2Fdocs").unwrap(), receive_dir_name("a/docs").unwrap());
+    }
+
+    #[test]
+    fn receive_dir_name_refuses_unsafe_paths() {
+        for folder in ["", "/", "..", "a/../b", "a\\b"] {
+            assert!(receive_dir_name(folder).is_err(), "{:?} was accepted", folder);
+        }
+    }
+
+    #[test]
+    fn legacy_decryption_skips_newer_transfers() {
+        let entry = |path: &str, file_type| DirEntry {
+            path: path.to_string(),
+            file_type,
+        };
+        let entries = vec![
+            entry("ft:our.os/send_to/RlRFRgEB", FileType::File),
+            entry("ft:our.os/send_to/other.os", FileType::Directory),
+            entry(&format!("ft:our.os/send_to/{}", INDEX_FILE), FileType::File),
+            entry(&format!("ft:our.os/send_to/{}", TRANSFER_KEY_FILE), FileType::File),
+            entry(&format!("ft:our.os/send_to/{}", CHECKPOINT_FILE), FileType::File),
+        ];
+        assert_eq!(encrypted_entries(entries), vec!["ft:our.os/send_to/RlRFRgEB".to_string()]);
+        assert!(legacy_folder(""));
+        assert!(legacy_folder("/"));
+        assert!(!legacy_folder("other.os/docs"));
+    }
+
+    #[test]
+    fn select_paths_picks_files_and_subtrees() {
+        let index: HashMap<String, String> = [
+            ("/notes/todo.md", "/a"),
+            ("/notes/old/done.md", "/b"),
+            ("/notes.md", "/c"),
+            ("/photo.jpg", "/d"),
+        ]
+        .iter()
+        .map(|(original, encrypted)| (original.to_string(), encrypted.to_string()))
+        .collect();
+        let names = |paths: &[&str]| -> Vec<String> {
+            let paths: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+            select_paths(&index, &paths)
+                .unwrap()
+                .into_iter()
+                .map(|(_, encrypted)| encrypted)
+                .collect()
+        };
+        // a folder doesn't take files that only share its prefix
+        assert_eq!(names(&["notes"]), vec!["/b", "/a"]);
+        assert_eq!(names(&["/notes/todo.md", "notes"]), vec!["/b", "/a"]);
+        assert_eq!(names(&["photo.jpg", "notes.md"]), vec!["/c", "/d"]);
+        assert_eq!(names(&["/"]).len(), 4);
+        assert!(select_paths(&index, &["missing".to_string()]).is_err());
+    }
+
+    #[test]
+    fn conflict_policy_keeps_live_and_encrypted_folders_apart() {
+        use ConflictPolicy::*;
+        for policy in [Rename, Overwrite, Merge, Reject] {
+            assert_eq!(conflict_policy(policy, false, false), policy);
+        }
+        for policy in [Rename, Overwrite, Merge] {
+            assert_eq!(conflict_policy(policy, true, false), Rename);
+            assert_eq!(conflict_policy(policy, true, true), Rename);
+        }
+        assert_eq!(conflict_policy(Reject, true, false), Reject);
+        assert_eq!(conflict_policy(Merge, false, true), Rename);
+        assert_eq!(conflict_policy(Overwrite, false, true), Overwrite);
+        assert_eq!(conflict_policy(Reject, false, true), Reject);
     }
 }
@@ -1,26 +1,292 @@
 use base64::{engine::general_purpose, Engine as _};
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 use kinode_process_lib::{
-    await_message, call_init, get_blob, println,
+    await_message, call_init, get_blob, println, timer,
     vfs::{create_file, open_dir, open_file, DirEntry, FileType, SeekFrom, VfsAction, VfsRequest},
-    Address, Message, Request,
+    Address, Message, Request, Response,
 };
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 
-use files_lib::encryption::{encrypt_data, CHUNK_SIZE};
-use files_lib::structs::{WorkerRequest, WorkerStatus};
-use files_lib::read_nested_dir_light;
+use files_lib::encryption::{shared_password, FileHeader, Kdf, SessionKey, CHUNK_SIZE, SALT_SIZE};
+use files_lib::hash::{root_hash, sha256_hex, FileHasher};
+use files_lib::structs::{
+    CheckpointEntry, DecryptWith, FileCheckpoint, ManifestEntry, TransferManifest,
+    TransferProgress, WorkerRequest, WorkerResponse, WorkerStatus, ACK_TIMEOUT, CHECKPOINT_FILE,
+    IDLE_CHECK, IDLE_TIMEOUT, MAX_SENDS, MAX_WINDOW, PROGRESS_INTERVAL, TRANSFER_KEY_FILE,
+};
+use files_lib::{is_bookkeeping, read_nested_dir_light, safe_join};
 
 wit_bindgen::generate!({
     path: "target/wit",
     world: "process-v0",
 });
 
-fn handle_message(our: &Address, receive_chunks_to_dir: &mut String) -> anyhow::Result<bool> {
-    let message = await_message()?;
+// everything the receiving worker keeps between chunks
+#[derive(Default)]
+struct ReceiverState {
+    // directory to which we will be storing received data
+    dir: String,
+    // file_path -> how much of it is stored, mirrored in the checkpoint journal
+    checkpoint: HashMap<String, FileCheckpoint>,
+    // upper bound on the total size of a transfer we accept
+    max_bytes: Option<u64>,
+    // what the sender is going to send, set once its manifest is accepted
+    manifest: Option<TransferManifest>,
+    // bytes stored so far, including those from a previous attempt
+    received_bytes: u64,
+    // set when decrypting chunks as they arrive
+    decrypt: Option<DecryptWith>,
+    // file_path as sent -> decrypted name and the header of the encrypted one
+    names: HashMap<String, (String, FileHeader)>,
+    // session keys by salt, each derived once
+    sessions: HashMap<[u8; SALT_SIZE], SessionKey>,
+    // node the sending worker must be on, and the token it must present
+    peer_node: String,
+    token: String,
+    // the sending worker, once its resume handshake checked out, nobody else may send us anything
+    peer: Option<Address>,
+    // set along with the manifest
+    progress: Option<ProgressMeter>,
+}
+
+// a chunk the sender still has to get acknowledged
+#[derive(Clone)]
+struct PendingChunk {
+    // path on our drive
+    path: String,
+    // path as the receiver knows it
+    file_path: String,
+    // part of the nonce of every chunk of the file
+    file_index: u32,
+    // encrypted in the session of an earlier attempt, rather than this one's
+    resumed: bool,
+    // plaintext size of the whole file
+    size: u64,
+    index: u64,
+}
+
+// everything the sending worker keeps while chunks are in flight
+struct SenderState {
+    target_worker: Address,
+    // None when not encrypting
+    session: Option<SessionKey>,
+    // the session files resumed from an earlier attempt were encrypted in
+    resumed_session: Option<SessionKey>,
+    // max number of chunks sent and not acknowledged yet
+    window: u64,
+    // chunks not sent yet
+    queue: VecDeque<PendingChunk>,
+    // (file_path, index) -> chunk sent and not acknowledged yet, and how many times it was sent
+    in_flight: HashMap<(String, u64), (PendingChunk, u32)>,
+    progress: ProgressMeter,
+    // no new chunks go out while set
+    paused: bool,
+}
+
+// counts the chunks moved, for the progress reports to our parent
+struct ProgressMeter {
+    // file_path -> chunks not moved yet
+    chunks_left: HashMap<String, u64>,
+    files_done: u64,
+    bytes_done: u64,
+    bytes_total: u64,
+    // bytes_done when this attempt started, throughput only counts this attempt
+    bytes_before: u64,
+    current_file: Option<String>,
+    started: Instant,
+    last_report: Instant,
+}
+
+impl ProgressMeter {
+    fn new() -> Self {
+        ProgressMeter {
+            chunks_left: HashMap::new(),
+            files_done: 0,
+            bytes_done: 0,
+            bytes_total: 0,
+            bytes_before: 0,
+            current_file: None,
+            started: Instant::now(),
+            last_report: Instant::now(),
+        }
+    }
+
+    // a file of `size` bytes in `chunks` chunks, `stored` of which an earlier attempt moved
+    fn add_file(&mut self, file_path: &str, size: u64, chunks: u64, stored: &BTreeSet<u64>) {
+        let stored_bytes: u64 = stored.iter().map(|index| chunk_len(size, *index)).sum();
+        let left = chunks.saturating_sub(stored.len() as u64);
+        if left == 0 {
+            self.files_done += 1;
+        }
+        self.chunks_left.insert(file_path.to_string(), left);
+        self.bytes_total += size;
+        self.bytes_done += stored_bytes;
+        self.bytes_before += stored_bytes;
+    }
 
-    if let Message::Request { ref body, .. } = message {
-        let request = serde_json::from_slice::<WorkerRequest>(body)?;
+    fn chunk_done(&mut self, file_path: &str, bytes: u64) {
+        self.bytes_done += bytes;
+        self.current_file = Some(file_path.to_string());
+        if let Some(left) = self.chunks_left.get_mut(file_path) {
+            *left = left.saturating_sub(1);
+            if *left == 0 {
+                self.files_done += 1;
+            }
+        }
+    }
+
+    // whether PROGRESS_INTERVAL passed since the last report
+    fn due(&mut self) -> bool {
+        if self.last_report.elapsed() < Duration::from_secs(PROGRESS_INTERVAL) {
+            return false;
+        }
+        self.last_report = Instant::now();
+        true
+    }
+
+    fn snapshot(&self) -> TransferProgress {
+        let moved = self.bytes_done - self.bytes_before;
+        let seconds = self.started.elapsed().as_secs_f64();
+        let throughput = if seconds > 0.0 { (moved as f64 / seconds) as u64 } else { 0 };
+        TransferProgress {
+            files_done: self.files_done,
+            files_total: self.chunks_left.len() as u64,
+            bytes_done: self.bytes_done,
+            bytes_total: self.bytes_total,
+            current_file: self.current_file.clone(),
+            throughput,
+            eta: (throughput > 0)
+                .then(|| self.bytes_total.saturating_sub(self.bytes_done) / throughput),
+        }
+    }
+}
+
+// when we last heard from the peer worker, a timer checks it every IDLE_CHECK seconds
+// the receiver's clock starts with the sender's resume handshake, until then our parent's watchdog covers it
+#[derive(Default)]
+struct Idle {
+    last_heard: Option<Instant>,
+    // a paused transfer is quiet on purpose
+    paused: bool,
+}
+
+impl Idle {
+    fn heard(&mut self) {
+        self.last_heard = Some(Instant::now());
+    }
+
+    fn timed_out(&self) -> Option<u64> {
+        let elapsed = self.last_heard?.elapsed().as_secs();
+        (!self.paused && elapsed >= IDLE_TIMEOUT).then_some(elapsed)
+    }
+}
+
+// a message not meant for us, dropped without ending the transfer
+// any other error ends it
+#[derive(Debug)]
+struct Ignored(String);
+
+impl std::fmt::Display for Ignored {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Ignored {}
+
+// returns the status to report once the worker should exit
+fn handle_message(
+    our: &Address,
+    sender: &mut Option<SenderState>,
+    receiver: &mut ReceiverState,
+    idle: &mut Idle,
+) -> anyhow::Result<Option<WorkerStatus>> {
+    let message = match await_message() {
+        Ok(message) => message,
+        // a chunk wasn't acknowledged in time
+        Err(send_error) => {
+            let Some(state) = sender.as_mut() else {
+                return Err(anyhow::anyhow!("worker: {:?}", send_error.kind()));
+            };
+            let Some(context) = send_error.context() else {
+                return Err(anyhow::anyhow!("worker: {:?}", send_error.kind()));
+            };
+            let key: (String, u64) = serde_json::from_slice(context)?;
+            return retransmit(state, &key);
+        }
+    };
+
+    // time to check whether the peer went quiet
+    if !message.is_request() && message.source() == &Address::new(our.node(), ("timer", "distro", "sys")) {
+        if let Some(secs) = idle.timed_out() {
+            println!("worker: nothing from the other side for {} seconds", secs);
+            return Ok(Some(WorkerStatus::TimedOut { secs }));
+        }
+        timer::set_timer(IDLE_CHECK * 1000, None);
+        return Ok(None);
+    }
+
+    // chunk acknowledgements from the receiver
+    if let Message::Response {
+        ref source,
+        ref body,
+        ref context,
+        ..
+    } = message
+    {
+        let Some(state) = sender.as_mut() else {
+            return Ok(None);
+        };
+        if source != &state.target_worker {
+            return Ok(None);
+        }
+        idle.heard();
+        let Some(context) = context else {
+            return Ok(None);
+        };
+        let key: (String, u64) = serde_json::from_slice(context)?;
+        match serde_json::from_slice(body)? {
+            WorkerResponse::ChunkStored => {
+                if let Some((chunk, _)) = state.in_flight.remove(&key) {
+                    state.progress.chunk_done(&chunk.file_path, chunk_len(chunk.size, chunk.index));
+                    if state.progress.due() {
+                        report(our, &WorkerStatus::Progress(state.progress.snapshot()))?;
+                    }
+                }
+                fill_window(state)?;
+                return finish_if_sent(state);
+            }
+            WorkerResponse::ChunkRejected { reason } => {
+                println!("worker: chunk {} of {} rejected: {}", key.1, key.0, reason);
+                return retransmit(state, &key);
+            }
+            WorkerResponse::ChunkRefused { reason } => {
+                println!("worker: chunk {} of {} refused: {}", key.1, key.0, reason);
+                return Ok(Some(WorkerStatus::Failed {
+                    reason,
+                    file: Some(key.0),
+                }));
+            }
+            _ => return Err(anyhow::anyhow!("worker: unexpected chunk reply")),
+        }
+    }
+
+    if let Message::Request { ref source, ref body, .. } = message {
+        let Ok(request) = serde_json::from_slice::<WorkerRequest>(body) else {
+            return Err(Ignored(format!("worker: malformed request from {}", source)).into());
+        };
+        check_source(our, sender, receiver, source, &request)?;
+        match &request {
+            WorkerRequest::InitializeSenderWorker { .. }
+            | WorkerRequest::InitializeReceiverWorker { .. } => timer::set_timer(IDLE_CHECK * 1000, None),
+            WorkerRequest::ResumeHandshake { .. }
+            | WorkerRequest::Keepalive
+            | WorkerRequest::Manifest(_)
+            | WorkerRequest::Chunk { .. } => idle.heard(),
+            _ => {}
+        }
         match request {
             // we will be sending chunks to `target_worker`, encrypting w/ `password_hash`, from directory `sending_from_dir`
             // if password_hash is None, we will not be encrypting
@@ -28,7 +294,11 @@ fn handle_message(our: &Address, receive_chunks_to_dir: &mut String) -> anyhow::
                 target_worker,
                 sending_dir,
                 password,
+                ephemeral_public_key,
+                window,
+                token,
             } => {
+                let window = window.clamp(1, MAX_WINDOW);
                 println!("sending_dir: {}", sending_dir);
                 // send to ourself for testing purposes
                 let target_worker: Address = target_worker.unwrap_or(our.clone());
@@ -43,118 +313,242 @@ fn handle_message(our: &Address, receive_chunks_to_dir: &mut String) -> anyhow::
                 // a flattened version of the nested dir
                 let dir = read_nested_dir_light(dir_entry)?;
 
-                // send each file from the folder to the server
+                // ask the receiver what it already has from a previous, interrupted attempt
+                let resume = match Request::new()
+                    .body(serde_json::to_vec(&WorkerRequest::ResumeHandshake { token })?)
+                    .target(target_worker.clone())
+                    .send_and_await_response(30)??
+                {
+                    Message::Response { body, .. } => match serde_json::from_slice(&body)? {
+                        WorkerResponse::Resume { completed } => completed,
+                        _ => return Err(anyhow::anyhow!("worker: unexpected resume handshake reply")),
+                    },
+                    _ => return Err(anyhow::anyhow!("worker: unexpected resume handshake reply")),
+                };
+                println!("worker: receiver already has {} files started", resume.len());
+
+                let (resumed_session, resume_by_path) = match_resumed(resume, password.as_deref());
+                // the key is derived once for the whole transfer
+                // a password from a key exchange is already random, one people pick needs a memory-hard kdf
+                let kdf = if ephemeral_public_key.is_some() {
+                    Kdf::Hkdf
+                } else {
+                    Kdf::SCRYPT_DEFAULT
+                };
+                // new files always get a session of their own, with a fresh salt and so a fresh key.
+                // we can't tell which file indexes the earlier session used up, so none of them is reused
+                let session = password
+                    .clone()
+                    .map(|password| SessionKey::new(&password, kdf));
+                let mut next_file_index = 0;
+
+                // everything we are about to send, described up front in the manifest
+                let mut manifest = TransferManifest {
+                    files: HashMap::new(),
+                    total_bytes: 0,
+                    total_chunks: 0,
+                    encrypted: password.is_some(),
+                    ephemeral_public_key,
+                    root_hash: String::new(),
+                };
+                // (path on our drive, file_path as sent, file index, resumed, file size, chunks the receiver already has)
+                let mut to_send: Vec<(String, String, u32, bool, u64, BTreeSet<u64>)> = Vec::new();
+                // hashing a big folder takes a while, both sides hear from us meanwhile
+                let mut last_keepalive = Instant::now();
+                let mut tick = || -> anyhow::Result<()> {
+                    if last_keepalive.elapsed() >= Duration::from_secs(IDLE_CHECK) {
+                        keepalive(our, &target_worker)?;
+                        last_keepalive = Instant::now();
+                    }
+                    Ok(())
+                };
+
                 for path in dir.keys() {
-                    let mut active_file = open_file(path, false, Some(5))?;
+                    let size = open_file(path, false, Some(5))?.metadata()?.len;
 
-                    // we have a target, chunk the data, and send it.
-                    let size = active_file.metadata()?.len;
-                    let _pos = active_file.seek(SeekFrom::Start(0))?;
+                    let sending_dir_str = sending_dir.trim_end_matches('/');
 
-                    let sending_dir_path = Path::new(sending_dir.as_str());
-                    let parent = sending_dir_path.parent().unwrap_or(sending_dir_path);
-                    let parent_str = parent.to_str().unwrap_or(sending_dir.as_str());
+                    // if full path is folder_transfer:astronaut.os/send_from/<folder>/notes/file.md
+                    // the relative path is /notes/file.md, the receiver decides where the folder goes
+                    let Some(relative_path) = path.strip_prefix(sending_dir_str) else {
+                        return Err(anyhow::anyhow!(
+                            "Path does not start with the expected prefix"
+                        ));
+                    };
+                    let relative_path = relative_path.to_string();
+                    // journal files of a folder we once received ourselves, the receiver keeps its own
+                    if is_bookkeeping(&relative_path) {
+                        continue;
+                    }
 
-                    let file_path =
-                        // encrypts file name
-                        if let Some(password) = password.clone() {
-                            // path: e.g. folder_transfer:astronaut.os/from/Obsidian Vault/file.md
-                            // we are sending: GAXPVM...0pihtLlOiu_E3A==
-
-                            let p = if path.starts_with(parent_str) {
-                                let rest_of_path = &path[parent_str.len()..].to_string();
-                                let encrypted_vec = &encrypt_data(
-                                    rest_of_path.as_bytes(),
-                                    password.as_str(),
-                                );
-                                format!("/{}", general_purpose::URL_SAFE.encode(&encrypted_vec))
-                            } else {
-                                return Err(anyhow::anyhow!(
-                                    "Path does not start with the expected prefix"
-                                ));
-                            };
-                            p
-                        } 
-                        // doesnt encrypt file name
-                        else {
-                            // if full path is folder_transfer:astronaut.os/from/Obsidian Vault/file.md
-                            // we are sending Obsidian Vault/file.mdđ
-        
-                            let p = if path.starts_with(parent_str) {
-                                path[parent_str.len()..].to_string()
-                            } else {
-                                return Err(anyhow::anyhow!(
-                                    "Path does not start with the expected prefix"
-                                ));
-                            };
-                            p
-                        };
-
-
-                    // chunking and sending
-                    //
                     // handling the edge case if there is 0 bytes, 
                     // we still want to send one chunk to make sure the empty file is transferred
-                    let num_chunks = if size != 0 {
-                        (size as f64 / CHUNK_SIZE as f64).ceil() as u64
-                    } else {
-                        1
+                    let num_chunks = chunk_count(size);
+
+                    // only resume files that didn't change since the stored chunks were sent,
+                    // re-encrypting different data under the same nonces would break the encryption.
+                    // the receiver has the digest of the file as sent, so it's checked under the session it was sent in
+                    let resumed = match resume_by_path.get(&relative_path) {
+                        Some(candidate @ (_, checkpoint, file_index)) => {
+                            let seal = resumed_session
+                                .as_ref()
+                                .map(|session| (session, *file_index));
+                            let digest = file_digest(path, seal, &mut tick)?;
+                            (checkpoint.digest.as_ref() == Some(&digest))
+                                .then_some((candidate, digest))
+                        }
+                        None => None,
                     };
 
-                    for i in 0..num_chunks {
-                        let offset = i * CHUNK_SIZE;
-                        let length = CHUNK_SIZE.min(size - offset); // size=file size
-                        let mut buffer = vec![0; length as usize];
-                        let pos = active_file.seek(SeekFrom::Current(0))?;
-                        active_file.read_at(&mut buffer)?;
+                    // chunks the receiver already stored are skipped
+                    let stored_chunks = resumed
+                        .as_ref()
+                        .map(|((_, checkpoint, _), _)| checkpoint.chunks.clone())
+                        .unwrap_or_default();
+                    let is_resumed = resumed.is_some();
 
-                        if let Some(pw_hash) = password.clone() {
-                            buffer = encrypt_data(&buffer, pw_hash.as_str());
+                    let (file_path, file_index, digest) = match (resumed, &session) {
+                        // keep the name the receiver already stores the file under
+                        (Some(((sent_path, _, file_index), digest)), _) => {
+                            (sent_path.clone(), *file_index, digest)
                         }
+                        // encrypts file name
+                        // relative path: e.g. /notes/file.md
+                        // we are sending: RlRFRgE...0pihtLlOiu_E3A==
+                        (None, Some(session)) => {
+                            let file_index = next_file_index;
+                            next_file_index += 1;
+                            let encrypted_vec = session.encrypt_name(file_index, &relative_path);
+                            (
+                                format!("/{}", general_purpose::URL_SAFE.encode(encrypted_vec)),
+                                file_index,
+                                file_digest(path, Some((session, file_index)), &mut tick)?,
+                            )
+                        }
+                        // doesnt encrypt file name
+                        (None, None) => (
+                            relative_path.clone(),
+                            0,
+                            file_digest(path, None, &mut tick)?,
+                        ),
+                    };
 
-                        Request::new()
-                            .body(serde_json::to_vec(&WorkerRequest::Chunk {
+                    manifest.total_bytes += size;
+                    manifest.total_chunks += num_chunks;
+                    manifest.files.insert(
+                        file_path.clone(),
+                        ManifestEntry {
+                            size,
+                            // the vfs doesn't expose modification times yet
+                            modified: None,
+                            digest,
+                            chunks: num_chunks,
+                        },
+                    );
+                    to_send.push((
+                        path.clone(),
+                        file_path,
+                        file_index,
+                        is_resumed,
+                        size,
+                        stored_chunks,
+                    ));
+                }
+                manifest.root_hash = root_hash(
+                    &manifest
+                        .files
+                        .iter()
+                        .map(|(file_path, entry)| (file_path.clone(), entry.digest.clone()))
+                        .collect(),
+                );
+
+                // the receiver may refuse the whole transfer before any data is sent
+                match Request::new()
+                    .body(serde_json::to_vec(&WorkerRequest::Manifest(manifest))?)
+                    .target(target_worker.clone())
+                    .send_and_await_response(30)??
+                {
+                    Message::Response { body, .. } => match serde_json::from_slice(&body)? {
+                        WorkerResponse::ManifestAccepted => {}
+                        WorkerResponse::ManifestRejected { reason } => {
+                            println!("worker: manifest rejected: {}", reason);
+                            return Ok(Some(WorkerStatus::Rejected { reason }));
+                        }
+                        _ => return Err(anyhow::anyhow!("worker: unexpected manifest reply")),
+                    },
+                    _ => return Err(anyhow::anyhow!("worker: unexpected manifest reply")),
+                }
+
+                let mut queue = VecDeque::new();
+                let mut progress = ProgressMeter::new();
+                for (path, file_path, file_index, resumed, size, stored_chunks) in to_send {
+                    let num_chunks = chunk_count(size);
+                    progress.add_file(&file_path, size, num_chunks, &stored_chunks);
+                    for index in 0..num_chunks {
+                        if !stored_chunks.contains(&index) {
+                            queue.push_back(PendingChunk {
+                                path: path.clone(),
                                 file_path: file_path.clone(),
-                                done: false,
-                                encrypted: password.is_some(),
-                            })?)
-                            .target(target_worker.clone())
-                            .blob_bytes(buffer.clone())
-                            .send()?;
+                                file_index,
+                                resumed,
+                                size,
+                                index,
+                            });
+                        }
                     }
                 }
-                println!("worker: sent everything");
-                Request::new()
-                    .body(serde_json::to_vec(&WorkerRequest::Chunk {
-                        file_path: "".to_string(),
-                        done: true,
-                        encrypted: password.is_some(),
-                    })?)
-                    .target(target_worker.clone())
-                    .send()?;
+                println!(
+                    "worker: sending {} chunks, {} at a time",
+                    queue.len(),
+                    window
+                );
 
-                return Ok(true);
+                let mut state = SenderState {
+                    target_worker,
+                    session,
+                    resumed_session,
+                    window,
+                    queue,
+                    in_flight: HashMap::new(),
+                    progress,
+                    paused: false,
+                };
+                // the clock starts once we're done reading the folder
+                idle.heard();
+                fill_window(&mut state)?;
+                let status = finish_if_sent(&state)?;
+                *sender = Some(state);
+                return Ok(status);
             }
 
             // we will be receivng chunks to directory `receive_to_dir`
-            WorkerRequest::InitializeReceiverWorker { receive_to_dir } => {
+            WorkerRequest::InitializeReceiverWorker {
+                receive_to_dir,
+                peer_node,
+                token,
+                max_bytes,
+                decrypt,
+            } => {
+                receiver.peer_node = peer_node;
+                receiver.token = token;
 
                 // start receiving data
                 let full_path = receive_to_dir;
-                *receive_chunks_to_dir = full_path.clone();
+                receiver.dir = full_path.clone();
+                receiver.max_bytes = max_bytes;
+                receiver.decrypt = decrypt;
                 
                 println!("starting to receive data for dir: {}", full_path);
 
-                // removing the dir, and creating a fresh one
-                let request: VfsRequest = VfsRequest {
-                    path: full_path.to_string(),
-                    action: VfsAction::RemoveDirAll,
-                };
-                let _message = Request::new()
-                    .target(("our", "vfs", "distro", "sys"))
-                    .body(serde_json::to_vec(&request)?)
-                    .send_and_await_response(5)?;
+                // a previous attempt left a checkpoint, keep what it stored
+                // a chunk torn by the interruption is not in the checkpoint, and gets rewritten at its offset
+                if let Some(stored) = load_checkpoint(&full_path) {
+                    println!("worker: resuming, {} files already started", stored.len());
+                    receiver.checkpoint = stored;
+                    return Ok(None);
+                }
 
+                // creating the dir, whatever is already in it was left there on purpose by the parent
                 let request: VfsRequest = VfsRequest {
                     path: full_path.to_string(),
                     action: VfsAction::CreateDirAll,
@@ -163,23 +557,228 @@ fn handle_message(our: &Address, receive_chunks_to_dir: &mut String) -> anyhow::
                     .target(("our", "vfs", "distro", "sys"))
                     .body(serde_json::to_vec(&request)?)
                     .send_and_await_response(5)?;
+                let _file = create_file(&checkpoint_path(&full_path), Some(5))?;
+            }
+
+            // our parent calls the transfer off
+            WorkerRequest::Abort { reason, discard } => {
+                if discard && !receiver.dir.is_empty() {
+                    discard_received(receiver)?;
+                }
+                return Ok(Some(WorkerStatus::Failed { reason, file: None }));
+            }
+
+            // only the sender has anything to hold off, the receiver just gets no chunks meanwhile
+            WorkerRequest::Pause => {
+                idle.paused = true;
+                if let Some(state) = sender.as_mut() {
+                    println!("worker: paused, {} chunks in flight", state.in_flight.len());
+                    state.paused = true;
+                }
+            }
+            WorkerRequest::Resume => {
+                idle.paused = false;
+                if idle.last_heard.is_some() {
+                    idle.heard();
+                }
+                if let Some(state) = sender.as_mut() {
+                    println!("worker: resumed");
+                    state.paused = false;
+                    fill_window(state)?;
+                    return finish_if_sent(state);
+                }
+            }
+
+            // sender wants to know which chunks to skip
+            WorkerRequest::ResumeHandshake { .. } => {
+                // check_source made sure it's the worker we expect
+                receiver.peer = Some(source.clone());
+                Response::new()
+                    .body(serde_json::to_vec(&WorkerResponse::Resume {
+                        completed: receiver.checkpoint.clone(),
+                    })?)
+                    .send()?;
+            }
+
+            // sender is still reading its folder, our parent hears we're alive too
+            WorkerRequest::Keepalive => {
+                report(our, &WorkerStatus::Heartbeat)?;
+            }
+
+            // sender describes the whole transfer before sending any data
+            WorkerRequest::Manifest(manifest) => {
+                let checked = check_manifest(&manifest, receiver.max_bytes)
+                    .and_then(|_| decrypt_names(receiver, &manifest));
+                if let Err(reason) = checked {
+                    println!("worker: rejecting manifest: {}", reason);
+                    Response::new()
+                        .body(serde_json::to_vec(&WorkerResponse::ManifestRejected {
+                            reason: reason.clone(),
+                        })?)
+                        .send()?;
+                    return Ok(Some(WorkerStatus::Rejected { reason }));
+                }
+                println!(
+                    "worker: accepted manifest: {} files, {} bytes, {} chunks",
+                    manifest.files.len(),
+                    manifest.total_bytes,
+                    manifest.total_chunks
+                );
+
+                // leftovers of an earlier attempt the sender isn't going to finish,
+                // e.g. encrypted under another key
+                let leftovers: Vec<String> = receiver
+                    .checkpoint
+                    .keys()
+                    .filter(|file_path| !manifest.files.contains_key(*file_path))
+                    .cloned()
+                    .collect();
+                for file_path in leftovers {
+                    let request: VfsRequest = VfsRequest {
+                        path: stored_path(receiver, &file_path)?,
+                        action: VfsAction::RemoveFile,
+                    };
+                    let _message = Request::new()
+                        .target(("our", "vfs", "distro", "sys"))
+                        .body(serde_json::to_vec(&request)?)
+                        .send_and_await_response(5)?;
+                    record_checkpoint(&receiver.dir, &file_path, None)?;
+                    receiver.checkpoint.remove(&file_path);
+                }
+
+                // keep the sender's half of the key exchange, needed to decrypt later
+                if let (Some(ephemeral_public_key), None) =
+                    (manifest.ephemeral_public_key, &receiver.decrypt)
+                {
+                    let path = format!("{}/{}", receiver.dir, TRANSFER_KEY_FILE);
+                    ensure_file(&receiver.dir, &path)?;
+                    let file = open_file(&path, false, Some(5))?;
+                    file.write(&serde_json::to_vec(&ephemeral_public_key)?)?;
+                }
+
+                // files that changed since their stored chunks were sent come again from scratch
+                let changed: Vec<String> = manifest
+                    .files
+                    .iter()
+                    .filter(|(file_path, entry)| {
+                        receiver
+                            .checkpoint
+                            .get(*file_path)
+                            .map(|file_checkpoint| file_checkpoint.digest.as_ref() != Some(&entry.digest))
+                            .unwrap_or(false)
+                    })
+                    .map(|(file_path, _)| file_path.clone())
+                    .collect();
+                for file_path in changed {
+                    record_checkpoint(&receiver.dir, &file_path, None)?;
+                    receiver.checkpoint.remove(&file_path);
+                }
+
+                // remember what each file should end up as, so a later attempt can tell
+                // whether the stored chunks still belong to the same content,
+                // and where it is stored when its name was decrypted
+                for (file_path, entry) in manifest.files.iter() {
+                    let file_checkpoint = receiver.checkpoint.entry(file_path.clone()).or_default();
+                    file_checkpoint.digest = Some(entry.digest.clone());
+                    if let Some((name, _)) = receiver.names.get(file_path) {
+                        file_checkpoint.stored_path = Some(name.clone());
+                    }
+                }
+                record_digests(&receiver.dir, &receiver.checkpoint)?;
+
+                receiver.received_bytes = receiver
+                    .checkpoint
+                    .iter()
+                    .filter(|(file_path, _)| manifest.files.contains_key(*file_path))
+                    .map(|(_, file_checkpoint)| file_checkpoint.bytes)
+                    .sum();
+                let mut progress = ProgressMeter::new();
+                for (file_path, entry) in manifest.files.iter() {
+                    let stored = receiver
+                        .checkpoint
+                        .get(file_path)
+                        .map(|file_checkpoint| file_checkpoint.chunks.clone())
+                        .unwrap_or_default();
+                    progress.add_file(file_path, entry.size, entry.chunks, &stored);
+                }
+                receiver.progress = Some(progress);
+                receiver.manifest = Some(manifest);
+                Response::new()
+                    .body(serde_json::to_vec(&WorkerResponse::ManifestAccepted)?)
+                    .send()?;
             }
 
-            // every time we receive a chunk, append to the file
-            WorkerRequest::Chunk { file_path, done, encrypted } => {
-                if done == true {
-                    return Ok(true);
+            // every time we receive a chunk, write it at the offset its index puts it at
+            // so chunks arriving out of order or more than once can't corrupt the file
+            WorkerRequest::Chunk {
+                file_path,
+                index,
+                done,
+                encrypted: _,
+                hash,
+            } => {
+                if done {
+                    let failed = verify_received(receiver)?;
+                    if !failed.is_empty() {
+                        // forget the failed files, so running the transfer again resends them
+                        for file_path in failed.iter() {
+                            record_checkpoint(&receiver.dir, file_path, None)?;
+                        }
+                        let files_done = receiver
+                            .manifest
+                            .as_ref()
+                            .map(|manifest| manifest.files.len() - failed.len())
+                            .unwrap_or(0) as u64;
+                        return Ok(Some(WorkerStatus::PartiallyDone { files_done, failed }));
+                    }
+                    // transfer complete, nothing left to resume
+                    let request: VfsRequest = VfsRequest {
+                        path: checkpoint_path(&receiver.dir),
+                        action: VfsAction::RemoveFile,
+                    };
+                    let _message = Request::new()
+                        .target(("our", "vfs", "distro", "sys"))
+                        .body(serde_json::to_vec(&request)?)
+                        .send_and_await_response(5)?;
+                    return Ok(Some(WorkerStatus::Done));
                 }
                 
-                println!("got file_path: {}", file_path);
                 let blob = get_blob();
+
+                // data that was never announced is not stored
+                let Some(manifest) = &receiver.manifest else {
+                    return Err(anyhow::anyhow!("worker: chunk before manifest"));
+                };
+                let Some(entry) = manifest.files.get(&file_path) else {
+                    return Err(anyhow::anyhow!("worker: {} is not in the manifest", file_path));
+                };
+                if index >= entry.chunks {
+                    return Err(anyhow::anyhow!(
+                        "worker: chunk {} of {} is past its last chunk",
+                        index,
+                        file_path
+                    ));
+                }
+
+                // duplicate delivery, e.g. a retransmission whose ack was late
+                if receiver
+                    .checkpoint
+                    .get(&file_path)
+                    .map(|file_checkpoint| file_checkpoint.chunks.contains(&index))
+                    .unwrap_or(false)
+                {
+                    Response::new()
+                        .body(serde_json::to_vec(&WorkerResponse::ChunkStored)?)
+                        .send()?;
+                    return Ok(None);
+                }
                 
-                let path_to_dir = receive_chunks_to_dir; // just skipping the initial '/'
-                let file_path = format!("{}{}", path_to_dir, &file_path);
+                // the manifest was checked already, a path that slipped through still fails here
+                let full_file_path = stored_path(receiver, &file_path)?;
 
-                let file_path_as_path = Path::new(file_path.as_str());
+                let file_path_as_path = Path::new(full_file_path.as_str());
                 let parent = file_path_as_path.parent().unwrap_or(file_path_as_path);
-                let parent_str = parent.to_str().unwrap_or(file_path.as_str());
+                let parent_str = parent.to_str().unwrap_or(full_file_path.as_str());
                 let request: VfsRequest = VfsRequest {
                     path: parent_str.to_string(),
                     action: VfsAction::CreateDirAll,
@@ -196,25 +795,657 @@ fn handle_message(our: &Address, receive_chunks_to_dir: &mut String) -> anyhow::
                         return Err(anyhow::anyhow!("worker: receive error: no blob"));
                     }
                 };
-                
-                // manually creating file if doesnt exist, since open_file(create:true) has an issue
-                let dir = open_dir(parent_str, false, Some(5))?;
-
-                let entries = dir.read()?;
-                if entries.contains(&DirEntry {
-                    path: file_path.clone(),
-                    file_type: FileType::File,
-                }) {
-                } else {
-                    let _file = create_file(&file_path, Some(5))?;
+
+                // a bad chunk is never written nor checkpointed, the sender sends it again
+                if sha256_hex(&bytes) != hash {
+                    println!("worker: chunk {} of {} doesn't match its hash", index, file_path);
+                    Response::new()
+                        .body(serde_json::to_vec(&WorkerResponse::ChunkRejected {
+                            reason: "hash mismatch".to_string(),
+                        })?)
+                        .send()?;
+                    return Ok(None);
                 }
 
-                let mut file = open_file(&file_path, false, Some(5))?;
-                file.append(&bytes)?;
+                // where the chunk goes and how long it must be follow from the manifest, not from the sender,
+                // so a chunk can't land past its file or make it bigger than announced
+                let plaintext_len = chunk_len(entry.size, index);
+                let stored_header = match chunk_header(receiver, manifest, &file_path) {
+                    Ok(header) => header,
+                    Err(reason) => {
+                        return reject_chunk(reason, file_path);
+                    }
+                };
+                let expected_len = stored_header
+                    .as_ref()
+                    .map(|header| header.stored_chunk_len(index, plaintext_len))
+                    .unwrap_or(plaintext_len);
+                if bytes.len() as u64 != expected_len {
+                    let reason = format!(
+                        "chunk {} of {} is {} bytes, expected {}",
+                        index,
+                        file_path,
+                        bytes.len(),
+                        expected_len
+                    );
+                    return reject_chunk(reason, file_path);
+                }
+                let offset = match &stored_header {
+                    Some(header) if !receiver.names.contains_key(&file_path) => header.chunk_offset(index),
+                    _ => index * CHUNK_SIZE,
+                };
+
+                // decrypting on receive, the plaintext goes at its place in the real file
+                let bytes = match receiver.names.get(&file_path) {
+                    Some((_, header)) => {
+                        let opened = receiver
+                            .sessions
+                            .get(&header.salt)
+                            .ok_or("no session key".to_string())
+                            .and_then(|session| session.open_chunk(header.file_index, index, &bytes));
+                        match opened {
+                            Ok(plaintext) => plaintext,
+                            Err(e) => {
+                                let reason = format!("can't decrypt chunk {} of {}: {}", index, file_path, e);
+                                return reject_chunk(reason, file_path);
+                            }
+                        }
+                    }
+                    None => bytes,
+                };
+
+                ensure_file(parent_str, &full_file_path)?;
+
+                let mut file = open_file(&full_file_path, false, Some(5))?;
+                // first chunk of this file in this transfer, drop leftovers of an unfinished attempt
+                if receiver
+                    .checkpoint
+                    .get(&file_path)
+                    .map(|file_checkpoint| file_checkpoint.chunks.is_empty())
+                    .unwrap_or(true)
+                {
+                    file.set_len(0)?;
+                }
+                let _pos = file.seek(SeekFrom::Start(offset))?;
+                file.write_all(&bytes)?;
+
+                // checkpoint only after the chunk is written
+                let file_checkpoint = receiver.checkpoint.entry(file_path.clone()).or_default();
+                file_checkpoint.chunks.insert(index);
+                file_checkpoint.bytes += bytes.len() as u64;
+                record_checkpoint(&receiver.dir, &file_path, Some((index, bytes.len() as u64)))?;
+                Response::new()
+                    .body(serde_json::to_vec(&WorkerResponse::ChunkStored)?)
+                    .send()?;
+
+                if let Some(progress) = receiver.progress.as_mut() {
+                    progress.chunk_done(&file_path, chunk_len(entry.size, index));
+                    if progress.due() {
+                        report(our, &WorkerStatus::Progress(progress.snapshot()))?;
+                    }
+                }
+
+                // plaintext sizes, so this is approximate for encrypted transfers
+                receiver.received_bytes += bytes.len() as u64;
+                println!(
+                    "got file_path: {} ({}/{} bytes)",
+                    file_path,
+                    receiver.received_bytes.min(manifest.total_bytes),
+                    manifest.total_bytes
+                );
+            }
+        }
+    }
+    Ok(None)
+}
+
+// relative path -> (file_path as the receiver knows it, its checkpoint, file index)
+type ResumeByPath = HashMap<String, (String, FileCheckpoint, u32)>;
+
+// what the receiver already has, by relative path.
+// encrypted file names are different on every attempt, so we decrypt them back.
+// their header tells the session they were encrypted in, which the resumed files continue,
+// so the stored chunks and the ones we are about to send share a key
+fn match_resumed(
+    resume: HashMap<String, FileCheckpoint>,
+    password: Option<&str>,
+) -> (Option<SessionKey>, ResumeByPath) {
+    let mut resumed_session: Option<SessionKey> = None;
+    let mut resume_by_path = HashMap::new();
+    for (sent_path, checkpoint) in resume {
+        let Some(password) = password else {
+            resume_by_path.insert(sent_path.clone(), (sent_path, checkpoint, 0));
+            continue;
+        };
+        let Ok(decoded) = general_purpose::URL_SAFE.decode(sent_path.trim_start_matches('/'))
+        else {
+            continue;
+        };
+        let Ok((header, encrypted_name)) = FileHeader::parse(&decoded) else {
+            continue;
+        };
+        // stored in an older format or chunk size, resend that file from scratch
+        if !header.is_current() {
+            continue;
+        }
+        let session = resumed_session
+            .get_or_insert_with(|| SessionKey::derive(password, header.kdf, &header.salt));
+        // stored in some other session, resend that file from scratch
+        if session.salt() != &header.salt {
+            continue;
+        }
+        let Ok(relative_path) = session.decrypt_name(header.file_index, encrypted_name) else {
+            // encrypted with some other password
+            continue;
+        };
+        resume_by_path.insert(relative_path, (sent_path, checkpoint, header.file_index));
+    }
+    (resumed_session, resume_by_path)
+}
+
+// a manifest is refused if it is inconsistent or bigger than we allow
+// (the vfs has no way to query free space, so the configured limit is what guards the disk)
+fn check_manifest(manifest: &TransferManifest, max_bytes: Option<u64>) -> Result<(), String> {
+    let total_bytes: u64 = manifest.files.values().map(|entry| entry.size).sum();
+    let total_chunks: u64 = manifest.files.values().map(|entry| entry.chunks).sum();
+    if total_bytes != manifest.total_bytes || total_chunks != manifest.total_chunks {
+        return Err("manifest totals don't add up".to_string());
+    }
+    if manifest.files.values().any(|entry| entry.chunks != chunk_count(entry.size)) {
+        return Err("manifest chunk counts don't match the file sizes".to_string());
+    }
+    // every file must stay within the receiving dir, and keep off our journal
+    for file_path in manifest.files.keys() {
+        safe_join("", file_path).map_err(|e| e.to_string())?;
+        if is_bookkeeping(file_path) {
+            return Err(format!("{} is reserved", file_path));
+        }
+    }
+    let digests: HashMap<String, String> = manifest
+        .files
+        .iter()
+        .map(|(file_path, entry)| (file_path.clone(), entry.digest.clone()))
+        .collect();
+    if root_hash(&digests) != manifest.root_hash {
+        return Err("manifest root hash doesn't match".to_string());
+    }
+    if let Some(max_bytes) = max_bytes {
+        if manifest.total_bytes > max_bytes {
+            return Err(format!(
+                "transfer is {} bytes, limit is {} bytes",
+                manifest.total_bytes, max_bytes
+            ));
+        }
+    }
+    Ok(())
+}
+
+// checks everything received against the manifest, returns the file_paths that did not verify
+fn verify_received(receiver: &ReceiverState) -> anyhow::Result<Vec<String>> {
+    let Some(manifest) = &receiver.manifest else {
+        return Err(anyhow::anyhow!("worker: transfer done without a manifest"));
+    };
+    let stored_digest = |file_path: &str| {
+        // a file decrypted on arrival is sealed again under its session,
+        // encrypted digests are of the ciphertext as sent
+        let seal = receiver.names.get(file_path).and_then(|(_, header)| {
+            receiver
+                .sessions
+                .get(&header.salt)
+                .map(|session| (session, header.file_index))
+        });
+        stored_path(receiver, file_path)
+            .and_then(|path| file_digest(&path, seal, || Ok(())))
+            .ok()
+    };
+    Ok(unverified_files(
+        manifest,
+        &receiver.checkpoint,
+        stored_digest,
+    ))
+}
+
+// the file_paths that are incomplete in the checkpoint or whose digest, as `stored_digest` reads it back,
+// doesn't match the manifest. all of them if the folder as a whole doesn't match its root hash
+fn unverified_files(
+    manifest: &TransferManifest,
+    checkpoint: &HashMap<String, FileCheckpoint>,
+    mut stored_digest: impl FnMut(&str) -> Option<String>,
+) -> Vec<String> {
+    let files = &manifest.files;
+
+    // file_path -> digest of the file as we stored it
+    let mut stored = HashMap::new();
+    let mut failed = Vec::new();
+    for (file_path, expected) in files.iter() {
+        let complete = checkpoint
+            .get(file_path)
+            .map(|file_checkpoint| file_checkpoint.chunks.len() as u64 == expected.chunks)
+            .unwrap_or(false);
+        let digest = if complete {
+            stored_digest(file_path)
+        } else {
+            None
+        };
+        match digest {
+            Some(digest) if digest == expected.digest => {
+                stored.insert(file_path.clone(), digest);
+            }
+            _ => failed.push(file_path.clone()),
+        }
+    }
+    // the whole folder as stored against the sender's root hash
+    if failed.is_empty() && root_hash(&stored) != manifest.root_hash {
+        println!("worker: root hash of the stored files doesn't match");
+        failed = files.keys().cloned().collect();
+    }
+    failed.sort();
+    failed
+}
+
+// decrypt-on-receive: works out the name each file is stored under,
+// and the session keys to decrypt its chunks with
+fn decrypt_names(receiver: &mut ReceiverState, manifest: &TransferManifest) -> Result<(), String> {
+    receiver.names.clear();
+    let Some(decrypt) = &receiver.decrypt else {
+        return Ok(());
+    };
+    // nothing to decrypt, files are stored as sent
+    if !manifest.encrypted {
+        return Ok(());
+    }
+    let password = match decrypt {
+        DecryptWith::Password(password) => password.clone(),
+        DecryptWith::KeyExchange { secret } => {
+            let Some(ephemeral_public_key) = &manifest.ephemeral_public_key else {
+                return Err("no ephemeral public key to decrypt with".to_string());
+            };
+            shared_password(secret, ephemeral_public_key)
+        }
+    };
+    let mut names = HashSet::new();
+    // the sender picks the salts, and every one costs a kdf run.
+    // all names share one salt and kdf, except that files we already hold chunks of
+    // may share the one of the attempt they were started in
+    let mut keys: [Option<([u8; SALT_SIZE], Kdf)>; 2] = [None, None];
+    for file_path in manifest.files.keys() {
+        let decoded = general_purpose::URL_SAFE
+            .decode(file_path.trim_start_matches('/'))
+            .map_err(|_| format!("{} is not an encrypted name", file_path))?;
+        let (header, encrypted_name) = FileHeader::parse(&decoded)?;
+        let resumed = receiver
+            .checkpoint
+            .get(file_path)
+            .map(|file_checkpoint| !file_checkpoint.chunks.is_empty())
+            .unwrap_or(false);
+        let key = keys[resumed as usize].get_or_insert((header.salt, header.kdf));
+        if *key != (header.salt, header.kdf) {
+            return Err("file names are encrypted under more than one key".to_string());
+        }
+        let session = receiver
+            .sessions
+            .entry(header.salt)
+            .or_insert_with(|| SessionKey::derive(&password, header.kdf, &header.salt));
+        let name = session
+            .decrypt_name(header.file_index, encrypted_name)
+            .map_err(|_| "can't decrypt file names, wrong key?".to_string())?;
+        safe_join("", &name).map_err(|e| e.to_string())?;
+        if is_bookkeeping(&name) {
+            return Err(format!("{} is reserved", name));
+        }
+        if !names.insert(name.clone()) {
+            return Err(format!("{} is sent twice", name));
+        }
+        receiver.names.insert(file_path.clone(), (name, header));
+    }
+    Ok(())
+}
+
+// where a file_path is stored on our drive
+// names come from the sender, so they are joined with safe_join and can't point outside our dir
+fn stored_path(receiver: &ReceiverState, file_path: &str) -> anyhow::Result<String> {
+    let name = receiver
+        .checkpoint
+        .get(file_path)
+        .and_then(|file_checkpoint| file_checkpoint.stored_path.clone())
+        .unwrap_or(file_path.to_string());
+    safe_join(&receiver.dir, &name)
+}
+
+// removes the files this transfer stored and its journal, leaving whatever else is in the dir,
+// e.g. what an earlier transfer merged in
+fn discard_received(receiver: &ReceiverState) -> anyhow::Result<()> {
+    println!("worker: discarding what was received in {}", receiver.dir);
+    for (file_path, file_checkpoint) in receiver.checkpoint.iter() {
+        if !file_checkpoint.chunks.is_empty() {
+            remove_file(&stored_path(receiver, file_path)?)?;
+        }
+    }
+    remove_file(&checkpoint_path(&receiver.dir))
+}
+
+fn remove_file(path: &str) -> anyhow::Result<()> {
+    let request: VfsRequest = VfsRequest {
+        path: path.to_string(),
+        action: VfsAction::RemoveFile,
+    };
+    let _message = Request::new()
+        .target(("our", "vfs", "distro", "sys"))
+        .body(serde_json::to_vec(&request)?)
+        .send_and_await_response(5)?;
+    Ok(())
+}
+
+// sha256 of a file read back chunk by chunk, or of the ciphertext its chunks are sealed into.
+// sealing is deterministic, so sender and receiver agree on it without the plaintext digest going anywhere
+fn file_digest(
+    file_path: &str,
+    seal: Option<(&SessionKey, u32)>,
+    mut tick: impl FnMut() -> anyhow::Result<()>,
+) -> anyhow::Result<String> {
+    let mut file = open_file(file_path, false, Some(5))?;
+    let size = file.metadata()?.len;
+    let mut hasher = FileHasher::new();
+    for index in 0..chunk_count(size) {
+        let mut buffer = vec![0; chunk_len(size, index) as usize];
+        let _pos = file.seek(SeekFrom::Start(index * CHUNK_SIZE))?;
+        file.read_at(&mut buffer)?;
+        match seal {
+            Some((session, file_index)) => {
+                hasher.update(&session.seal_chunk(file_index, index, &buffer))
+            }
+            None => hasher.update(&buffer),
+        }
+        tick()?;
+    }
+    Ok(hasher.finish())
+}
+
+// the process that spawned us, the only one that may initialize us and the one we report to
+fn parent(our: &Address) -> Address {
+    Address::new(our.node(), ("folder_transfer", our.package(), our.publisher()))
+}
+
+// only our parent may initialize us, once, and only our peer worker may send us a transfer
+fn check_source(
+    our: &Address,
+    sender: &Option<SenderState>,
+    receiver: &ReceiverState,
+    source: &Address,
+    request: &WorkerRequest,
+) -> anyhow::Result<()> {
+    let initialized = sender.is_some() || !receiver.dir.is_empty();
+    let allowed = match request {
+        WorkerRequest::InitializeSenderWorker { .. }
+        | WorkerRequest::InitializeReceiverWorker { .. } => !initialized && source == &parent(our),
+        WorkerRequest::ResumeHandshake { token } => {
+            !receiver.dir.is_empty()
+                && source.node() == receiver.peer_node
+                && token == &receiver.token
+                && (receiver.peer.is_none() || receiver.peer.as_ref() == Some(source))
+        }
+        WorkerRequest::Keepalive | WorkerRequest::Manifest(_) | WorkerRequest::Chunk { .. } => {
+            receiver.peer.as_ref() == Some(source)
+        }
+        WorkerRequest::Abort { .. } | WorkerRequest::Pause | WorkerRequest::Resume => {
+            initialized && source == &parent(our)
+        }
+    };
+    if !allowed {
+        return Err(Ignored(format!("worker: ignoring request from {}", source)).into());
+    }
+    Ok(())
+}
+
+// tells the receiver and our parent that the sender is busy, not gone
+fn keepalive(our: &Address, target_worker: &Address) -> anyhow::Result<()> {
+    Request::new()
+        .body(serde_json::to_vec(&WorkerRequest::Keepalive)?)
+        .target(target_worker.clone())
+        .send()?;
+    report(our, &WorkerStatus::Heartbeat)
+}
+
+// tells our parent how the transfer is going
+fn report(our: &Address, status: &WorkerStatus) -> anyhow::Result<()> {
+    Request::new()
+        .body(serde_json::to_vec(status)?)
+        .target(parent(our))
+        .send()
+}
+
+// plaintext bytes in chunk `index` of a file of `size` bytes
+fn chunk_len(size: u64, index: u64) -> u64 {
+    CHUNK_SIZE.min(size.saturating_sub(index * CHUNK_SIZE))
+}
+
+// chunks in a file of `size` bytes, an empty file still gets one
+fn chunk_count(size: u64) -> u64 {
+    if size != 0 {
+        (size as f64 / CHUNK_SIZE as f64).ceil() as u64
+    } else {
+        1
+    }
+}
+
+// the header a file's chunks are encrypted under, None for plaintext
+// the sender only encrypts in the current format, with the current chunk size
+fn chunk_header(
+    receiver: &ReceiverState,
+    manifest: &TransferManifest,
+    file_path: &str,
+) -> Result<Option<FileHeader>, String> {
+    if !manifest.encrypted {
+        return Ok(None);
+    }
+    let header = match receiver.names.get(file_path) {
+        Some((_, header)) => header.clone(),
+        None => {
+            let decoded = general_purpose::URL_SAFE
+                .decode(file_path.trim_start_matches('/'))
+                .map_err(|_| format!("{} is not an encrypted name", file_path))?;
+            FileHeader::parse(&decoded)?.0
+        }
+    };
+    if !header.is_current() {
+        return Err(format!("{} is not in the current format", file_path));
+    }
+    Ok(Some(header))
+}
+
+// a chunk that arrived intact but can't be stored, sending it again won't help
+fn reject_chunk(reason: String, file_path: String) -> anyhow::Result<Option<WorkerStatus>> {
+    Response::new()
+        .body(serde_json::to_vec(&WorkerResponse::ChunkRefused {
+            reason: reason.clone(),
+        })?)
+        .send()?;
+    Ok(Some(WorkerStatus::Failed {
+        reason,
+        file: Some(file_path),
+    }))
+}
+
+// reads, encrypts and sends one chunk, expecting an ack within ACK_TIMEOUT
+fn send_chunk(state: &SenderState, chunk: &PendingChunk) -> anyhow::Result<()> {
+    let mut active_file = open_file(&chunk.path, false, Some(5))?;
+    let offset = chunk.index * CHUNK_SIZE;
+    let length = chunk_len(chunk.size, chunk.index);
+    let mut buffer = vec![0; length as usize];
+    let _pos = active_file.seek(SeekFrom::Start(offset))?;
+    active_file.read_at(&mut buffer)?;
+
+    let session = if chunk.resumed {
+        &state.resumed_session
+    } else {
+        &state.session
+    };
+    if let Some(session) = session {
+        buffer = session.seal_chunk(chunk.file_index, chunk.index, &buffer);
+    }
+
+    Request::new()
+        .body(serde_json::to_vec(&WorkerRequest::Chunk {
+            file_path: chunk.file_path.clone(),
+            index: chunk.index,
+            done: false,
+            encrypted: state.session.is_some(),
+            hash: sha256_hex(&buffer),
+        })?)
+        .target(state.target_worker.clone())
+        .blob_bytes(buffer)
+        .expects_response(ACK_TIMEOUT)
+        .context(serde_json::to_vec(&(chunk.file_path.clone(), chunk.index))?)
+        .send()?;
+    Ok(())
+}
+
+// sends queued chunks until `window` of them are in flight
+fn fill_window(state: &mut SenderState) -> anyhow::Result<()> {
+    while !state.paused && (state.in_flight.len() as u64) < state.window {
+        let Some(chunk) = state.queue.pop_front() else {
+            break;
+        };
+        send_chunk(state, &chunk)?;
+        state
+            .in_flight
+            .insert((chunk.file_path.clone(), chunk.index), (chunk, 1));
+    }
+    Ok(())
+}
+
+// sends an unacknowledged chunk again, giving up on the transfer after MAX_SENDS attempts
+fn retransmit(
+    state: &mut SenderState,
+    key: &(String, u64),
+) -> anyhow::Result<Option<WorkerStatus>> {
+    let Some((chunk, sends)) = state.in_flight.get(key).cloned() else {
+        // acknowledged in the meantime
+        return Ok(None);
+    };
+    if sends >= MAX_SENDS {
+        return Ok(Some(WorkerStatus::Failed {
+            reason: format!(
+                "chunk {} of {} not acknowledged after {} attempts",
+                key.1, key.0, sends
+            ),
+            file: Some(key.0.clone()),
+        }));
+    }
+    println!("worker: resending chunk {} of {}", key.1, key.0);
+    send_chunk(state, &chunk)?;
+    state.in_flight.insert(key.clone(), (chunk, sends + 1));
+    Ok(None)
+}
+
+// once every chunk is acknowledged, tells the receiver we are done
+fn finish_if_sent(state: &SenderState) -> anyhow::Result<Option<WorkerStatus>> {
+    if !state.queue.is_empty() || !state.in_flight.is_empty() {
+        return Ok(None);
+    }
+    println!("worker: sent everything");
+    Request::new()
+        .body(serde_json::to_vec(&WorkerRequest::Chunk {
+            file_path: "".to_string(),
+            index: 0,
+            done: true,
+            encrypted: state.session.is_some(),
+            hash: String::new(),
+        })?)
+        .target(state.target_worker.clone())
+        .send()?;
+    Ok(Some(WorkerStatus::Done))
+}
+
+fn checkpoint_path(receive_chunks_to_dir: &str) -> String {
+    format!("{}/{}", receive_chunks_to_dir, CHECKPOINT_FILE)
+}
+
+// manually creating file if doesnt exist, since open_file(create:true) has an issue
+fn ensure_file(parent_path: &str, file_path: &str) -> anyhow::Result<()> {
+    let dir = open_dir(parent_path, false, Some(5))?;
+
+    let entries = dir.read()?;
+    if !entries.contains(&DirEntry {
+        path: file_path.to_string(),
+        file_type: FileType::File,
+    }) {
+        let _file = create_file(file_path, Some(5))?;
+    }
+    Ok(())
+}
+
+// replays the checkpoint journal
+// a line torn by a crash while writing it is ignored
+fn load_checkpoint(receive_chunks_to_dir: &str) -> Option<HashMap<String, FileCheckpoint>> {
+    let file = open_file(&checkpoint_path(receive_chunks_to_dir), false, Some(5)).ok()?;
+    let contents = file.read().ok()?;
+    let mut checkpoint: HashMap<String, FileCheckpoint> = HashMap::new();
+    for line in String::from_utf8_lossy(&contents).lines() {
+        let Ok(entry) = serde_json::from_str::<CheckpointEntry>(line) else {
+            continue;
+        };
+        match (entry.chunk, entry.digest) {
+            (Some((index, length)), _) => {
+                let file_checkpoint = checkpoint.entry(entry.file_path).or_default();
+                if file_checkpoint.chunks.insert(index) {
+                    file_checkpoint.bytes += length;
+                }
+            }
+            (None, Some(digest)) => {
+                let file_checkpoint = checkpoint.entry(entry.file_path).or_default();
+                file_checkpoint.digest = Some(digest);
+                file_checkpoint.stored_path = entry.stored_path;
+            }
+            (None, None) => {
+                checkpoint.remove(&entry.file_path);
             }
         }
     }
-    Ok(false)
+    Some(checkpoint)
+}
+
+// appends one line to the journal, so each chunk costs a single small write
+// `chunk` is (index, length) of a stored chunk, None forgets the whole file
+fn record_checkpoint(
+    receive_chunks_to_dir: &str,
+    file_path: &str,
+    chunk: Option<(u64, u64)>,
+) -> anyhow::Result<()> {
+    let path = checkpoint_path(receive_chunks_to_dir);
+    let mut line = serde_json::to_vec(&CheckpointEntry {
+        file_path: file_path.to_string(),
+        chunk,
+        digest: None,
+        stored_path: None,
+    })?;
+    line.push(b'\n');
+    let mut file = open_file(&path, false, Some(5))?;
+    file.append(&line)?;
+    Ok(())
+}
+
+// one line per file with a digest, in a single write
+fn record_digests(
+    receive_chunks_to_dir: &str,
+    checkpoint: &HashMap<String, FileCheckpoint>,
+) -> anyhow::Result<()> {
+    let mut lines = Vec::new();
+    for (file_path, file_checkpoint) in checkpoint.iter() {
+        if file_checkpoint.digest.is_none() {
+            continue;
+        }
+        lines.extend(serde_json::to_vec(&CheckpointEntry {
+            file_path: file_path.clone(),
+            chunk: None,
+            digest: file_checkpoint.digest.clone(),
+            stored_path: file_checkpoint.stored_path.clone(),
+        })?);
+        lines.push(b'\n');
+    }
+    let mut file = open_file(&checkpoint_path(receive_chunks_to_dir), false, Some(5))?;
+    file.append(&lines)?;
+    Ok(())
 }
 
 call_init!(init);
@@ -222,30 +1453,217 @@ fn init(our: Address) {
     println!("worker: begin");
     let start = std::time::Instant::now();
 
-    // directory to which we will be storing received data
-    let mut receive_chunks_to_dir = String::new();
+    let mut sender: Option<SenderState> = None;
+    let mut receiver = ReceiverState::default();
+    let mut idle = Idle::default();
 
     loop {
-        match handle_message(&our,&mut receive_chunks_to_dir) {
-            Ok(exit) => {
-                if exit {
+        match handle_message(&our, &mut sender, &mut receiver, &mut idle) {
+            Ok(status) => {
+                if let Some(status) = status {
                     println!(
-                        "worker: done: , took {:?}",
+                        "worker: done: {:?}, took {:?}",
+                        status,
                         start.elapsed()
                     );
-                    let _ = Request::new()
-                    .body(serde_json::to_vec(&WorkerStatus::Done).unwrap())
-                    .target(
-                        Address::new(
-                        our.node(),
-                        ("folder_transfer", "folder_transfer", "astronaut.os")))
-                    .send();
+                    let _ = report(&our, &status);
                     break;
                 }
             }
+            Err(e) if e.is::<Ignored>() => {
+                println!("{}", e);
+            }
+            // anything else leaves the transfer in a state we can't go on from
             Err(e) => {
                 println!("worker error: {:?}", e);
+                let _ = report(
+                    &our,
+                    &WorkerStatus::Failed {
+                        reason: e.to_string(),
+                        file: None,
+                    },
+                );
+                break;
             }
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(files: &[(&str, u64)]) -> TransferManifest {
+        let files: HashMap<String, ManifestEntry> = files
+            .iter()
+            .map(|(file_path, size)| {
+                let entry = ManifestEntry {
+                    size: *size,
+                    modified: None,
+                    digest: sha256_hex(file_path.as_bytes()),
+                    chunks: chunk_count(*size),
+                };
+                (file_path.to_string(), entry)
+            })
+            .collect();
+        let digests = files
+            .iter()
+            .map(|(file_path, entry)| (file_path.clone(), entry.digest.clone()))
+            .collect();
+        TransferManifest {
+            total_bytes: files.values().map(|entry| entry.size).sum(),
+            total_chunks: files.values().map(|entry| entry.chunks).sum(),
+            root_hash: root_hash(&digests),
+            files,
+            encrypted: false,
+            ephemeral_public_key: None,
+        }
+    }
+
+    #[test]
+    fn check_manifest_accepts_a_consistent_manifest() {
+        let manifest = manifest(&[
+            ("/notes/todo.md", 10),
+            ("/empty", 0),
+            ("/big", 3 * CHUNK_SIZE + 1),
+        ]);
+        assert!(check_manifest(&manifest, None).is_ok());
+        assert!(check_manifest(&manifest, Some(manifest.total_bytes)).is_ok());
+        assert!(check_manifest(&manifest, Some(manifest.total_bytes - 1)).is_err());
+    }
+
+    #[test]
+    fn check_manifest_refuses_inconsistent_manifests() {
+        let good = manifest(&[("/a", 10), ("/b", CHUNK_SIZE + 1)]);
+
+        let mut totals = good.clone();
+        totals.total_bytes -= 1;
+        assert!(check_manifest(&totals, None).is_err());
+
+        let mut chunks = good.clone();
+        chunks.files.get_mut("/a").unwrap().chunks = 2;
+        chunks.total_chunks += 1;
+        assert!(check_manifest(&chunks, None).is_err());
+
+        let mut digest = good.clone();
+        digest.files.get_mut("/b").unwrap().digest = sha256_hex(b"changed");
+        assert!(check_manifest(&digest, None).is_err());
+    }
+
+    #[test]
+    fn check_manifest_keeps_files_inside_the_dir() {
+        assert!(check_manifest(&manifest(&[("/../outside", 1)]), None).is_err());
+        assert!(check_manifest(&manifest(&[(&format!("/{}", CHECKPOINT_FILE), 1)]), None).is_err());
+        assert!(
+            check_manifest(&manifest(&[(&format!("/{}", TRANSFER_KEY_FILE), 1)]), None).is_err()
+        );
+    }
+
+    #[test]
+    fn chunks_cover_the_whole_file() {
+        // an empty file still gets one, empty, chunk
+        assert_eq!(chunk_count(0), 1);
+        assert_eq!(chunk_len(0, 0), 0);
+        assert_eq!(chunk_count(1), 1);
+        assert_eq!(chunk_count(CHUNK_SIZE), 1);
+        assert_eq!(chunk_count(CHUNK_SIZE + 1), 2);
+        assert_eq!(chunk_len(CHUNK_SIZE + 1, 0), CHUNK_SIZE);
+        assert_eq!(chunk_len(CHUNK_SIZE + 1, 1), 1);
+        // past the end of the file
+        assert_eq!(chunk_len(CHUNK_SIZE + 1, 2), 0);
+        for size in [1, 10, CHUNK_SIZE - 1, CHUNK_SIZE, 3 * CHUNK_SIZE + 7] {
+            let total: u64 = (0..chunk_count(size))
+                .map(|index| chunk_len(size, index))
+                .sum();
+            assert_eq!(total, size);
+            assert!(chunk_len(size, chunk_count(size) - 1) > 0);
+        }
+    }
+
+    #[test]
+    fn match_resumed_decrypts_names_of_one_session() {
+        let started = |index: u64| FileCheckpoint {
+            chunks: BTreeSet::from([index]),
+            ..Default::default()
+        };
+        let session = SessionKey::new("password", Kdf::Hkdf);
+        let sent = |file_index: u32, name: &str| {
+            let encrypted = session.encrypt_name(file_index, name);
+            format!("/{}", general_purpose::URL_SAFE.encode(encrypted))
+        };
+        let resume = HashMap::from([
+            (sent(0, "/notes/todo.md"), started(0)),
+            (sent(4, "/photo.jpg"), started(1)),
+            ("/not a name".to_string(), started(2)),
+        ]);
+
+        let (resumed_session, by_path) = match_resumed(resume.clone(), Some("password"));
+        assert_eq!(resumed_session.unwrap().salt(), session.salt());
+        assert_eq!(by_path.len(), 2);
+        let (sent_path, checkpoint, file_index) = &by_path["/photo.jpg"];
+        assert_eq!(sent_path, &sent(4, "/photo.jpg"));
+        assert_eq!(checkpoint.chunks, BTreeSet::from([1]));
+        assert_eq!(*file_index, 4);
+
+        // under another password nothing is resumed
+        let (_, by_path) = match_resumed(resume.clone(), Some("other"));
+        assert!(by_path.is_empty());
+
+        // unencrypted names are taken as they are
+        let (resumed_session, by_path) = match_resumed(resume, None);
+        assert!(resumed_session.is_none());
+        assert_eq!(by_path.len(), 3);
+        assert_eq!(by_path["/not a name"].2, 0);
+    }
+
+    fn complete(manifest: &TransferManifest) -> HashMap<String, FileCheckpoint> {
+        manifest
+            .files
+            .iter()
+            .map(|(file_path, entry)| {
+                let file_checkpoint = FileCheckpoint {
+                    chunks: (0..entry.chunks).collect(),
+                    bytes: entry.size,
+                    digest: Some(entry.digest.clone()),
+                    stored_path: None,
+                };
+                (file_path.clone(), file_checkpoint)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unverified_files_checks_every_file_and_the_root_hash() {
+        let manifest = manifest(&[("/a", 10), ("/b", CHUNK_SIZE + 1), ("/c", 0)]);
+        let stored = |file_path: &str| Some(sha256_hex(file_path.as_bytes()));
+        assert!(unverified_files(&manifest, &complete(&manifest), stored).is_empty());
+
+        // missing and incomplete files
+        let mut checkpoint = complete(&manifest);
+        checkpoint.remove("/a");
+        checkpoint.get_mut("/b").unwrap().chunks.remove(&1);
+        assert_eq!(
+            unverified_files(&manifest, &checkpoint, stored),
+            vec!["/a", "/b"]
+        );
+
+        // a file that reads back different, or not at all
+        let changed = |file_path: &str| match file_path {
+            "/b" => Some(sha256_hex(b"changed")),
+            "/c" => None,
+            _ => stored(file_path),
+        };
+        assert_eq!(
+            unverified_files(&manifest, &complete(&manifest), changed),
+            vec!["/b", "/c"]
+        );
+
+        // every file checks out on its own, but not the folder as a whole
+        let mut renamed = manifest.clone();
+        renamed.root_hash = sha256_hex(b"other folder");
+        assert_eq!(
+            unverified_files(&renamed, &complete(&renamed), stored),
+            vec!["/a", "/b", "/c"]
+        );
+    }
+}